@@ -0,0 +1,44 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Stops a running `Mailer::post` from another thread or a signal
+/// handler. Once cancelled no more mails are handed out, transactions
+/// already under way are finished, every channel says QUIT and `post`
+/// returns what it got so far, with `Error::Cancelled` for the rest.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        let (cancelled, changed) = self.inner.as_ref();
+        *cancelled.lock().unwrap() = true;
+        changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Sleeps for `duration` or until cancelled, and tells which.
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let (cancelled, changed) = self.inner.as_ref();
+        let end = Instant::now() + duration;
+        let mut guard = cancelled.lock().unwrap();
+        while !*guard {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            guard = changed.wait_timeout(guard, left).unwrap().0;
+        }
+        *guard
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{Error, Result};
+
+/// Longest line SMTP allows, not counting the CRLF (RFC 5321 section 4.5.3.1.6).
+const MAX_LINE_LENGTH: usize = 998;
+
+/// Encodes a message for the DATA command as it is written out in
+/// chunks: bare CR and LF become CRLF and a dot starting a line is
+/// doubled (RFC 5321 section 4.5.2). Lines over the length limit, and
+/// 8-bit data when the server only takes 7-bit, are refused.
+pub(crate) struct DataEncoder {
+    line_start: bool,
+    last_cr: bool,
+    line_length: usize,
+    eight_bit: bool,
+}
+
+impl DataEncoder {
+    pub(crate) fn new(eight_bit: bool) -> DataEncoder {
+        DataEncoder {
+            line_start: true,
+            last_cr: false,
+            line_length: 0,
+            eight_bit,
+        }
+    }
+
+    fn end_line(&mut self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"\r\n");
+        self.line_start = true;
+        self.last_cr = false;
+        self.line_length = 0;
+    }
+
+    pub(crate) fn encode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        out.reserve(input.len() + input.len() / 32);
+        for &b in input {
+            if self.last_cr {
+                self.end_line(out);
+                if b == b'\n' {
+                    continue;
+                }
+            }
+            match b {
+                b'\r' => self.last_cr = true,
+                b'\n' => self.end_line(out),
+                _ => {
+                    if !self.eight_bit && !b.is_ascii() {
+                        return Err(Error::MIMENotSupported);
+                    }
+                    self.line_length += 1;
+                    if self.line_length > MAX_LINE_LENGTH {
+                        return Err(Error::LineTooLong);
+                    }
+                    if self.line_start && b == b'.' {
+                        out.push(b'.');
+                    }
+                    out.push(b);
+                    self.line_start = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Terminates the last line if needed and appends the final dot.
+    pub(crate) fn finish(mut self, out: &mut Vec<u8>) {
+        if self.last_cr || !self.line_start {
+            self.end_line(out);
+        }
+        out.extend_from_slice(b".\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `input` in chunks of `size` bytes.
+    fn encode(input: &[u8], size: usize) -> Result<Vec<u8>> {
+        let mut encoder = DataEncoder::new(true);
+        let mut out = vec![];
+        for chunk in input.chunks(size) {
+            encoder.encode(chunk, &mut out)?;
+        }
+        encoder.finish(&mut out);
+        Ok(out)
+    }
+
+    #[test]
+    fn dots_starting_lines_are_doubled() {
+        assert_eq!(
+            encode(b".first\r\nmid.dle\r\n.\r\n..two", 1024).unwrap(),
+            b"..first\r\nmid.dle\r\n..\r\n...two\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn bare_line_ends_become_crlf() {
+        assert_eq!(
+            encode(b"a\nb\rc\r\n\n", 1024).unwrap(),
+            b"a\r\nb\r\nc\r\n\r\n.\r\n"
+        );
+        assert_eq!(encode(b"trailing\r", 1024).unwrap(), b"trailing\r\n.\r\n");
+    }
+
+    #[test]
+    fn chunk_boundaries_change_nothing() {
+        let input = b"one\r\n.two\r\nthree\r\r\n\n.\r\n.four\r";
+        let whole = encode(input, input.len()).unwrap();
+        for size in 1..input.len() {
+            assert_eq!(encode(input, size).unwrap(), whole, "chunks of {}", size);
+        }
+    }
+
+    #[test]
+    fn long_lines_and_unexpected_8bit_are_refused() {
+        let line = vec![b'a'; MAX_LINE_LENGTH];
+        assert!(encode(&line, 100).is_ok());
+        let line = vec![b'a'; MAX_LINE_LENGTH + 1];
+        assert!(matches!(encode(&line, 100), Err(Error::LineTooLong)));
+        let mut out = vec![];
+        let result = DataEncoder::new(false).encode("café".as_bytes(), &mut out);
+        assert!(matches!(result, Err(Error::MIMENotSupported)));
+    }
+}
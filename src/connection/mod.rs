@@ -1,18 +1,25 @@
+mod data;
+pub(crate) mod net;
 mod parser;
 mod protocol;
+mod proxy;
 
 use super::{
-    check_address, Config, Credentials, Error, Logger, Mail, Result, Server, ServerMeta, Support,
+    check_address, CancelToken, Config, Credentials, Error, Event, Logger, Mail, Payload, Result,
+    Server, ServerMeta, Support,
 };
+use data::DataEncoder;
 use protocol::{get_auth_login, AuthMech, Command, EhloLine, Line, StatusCode};
-use rustls;
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rustls::{OwnedTrustAnchor, RootCertStore};
 
+/// How much of a message is written under one DATA-block timeout.
+const DATA_BLOCK_SIZE: usize = 64 * 1024;
+
 type TlsCon = rustls::ClientConnection;
 
 fn create_tls_conn(server_address: &str) -> TlsCon {
@@ -29,7 +36,7 @@ fn create_tls_conn(server_address: &str) -> TlsCon {
         .with_root_certificates(root_store)
         .with_no_client_auth();
 
-    return TlsCon::new(Arc::new(config), server_address.try_into().unwrap()).unwrap();
+    TlsCon::new(Arc::new(config), server_address.try_into().unwrap()).unwrap()
 }
 
 fn stream_recv_reply<T>(stream: &mut T, logger: &mut impl Logger) -> Result<Vec<Line>>
@@ -60,7 +67,16 @@ where
     pub(crate) server: Server,
     pub(crate) tlscon: Option<TlsCon>,
     pub(crate) stream: TcpStream,
+    pub(crate) address: SocketAddr,
     pub(crate) logger: L,
+    /// When the mail being sent must be done by, if it has a deadline.
+    pub(crate) deadline: Option<Instant>,
+    /// Whether the session is up, from the greeting until it is ended.
+    pub(crate) open: bool,
+    /// What the session was opened with, to open it again after it ends.
+    pub(crate) credentials: Option<Credentials>,
+    /// Cuts the wait between retries short.
+    pub(crate) cancel: CancelToken,
 }
 
 impl<L> MailerConnection<L>
@@ -71,15 +87,21 @@ where
         server: Server,
         config: Config,
         stream: TcpStream,
+        address: SocketAddr,
         logger: L,
     ) -> MailerConnection<L> {
         MailerConnection {
-            name: "me".to_string(),
+            name: net::ehlo_name(&config),
             server,
             config,
             tlscon: None,
+            address,
             stream,
             logger,
+            deadline: None,
+            open: false,
+            credentials: None,
+            cancel: CancelToken::new(),
         }
     }
     pub(crate) fn recv_reply(&mut self) -> Result<Vec<Line>> {
@@ -93,16 +115,28 @@ where
             stream_recv_reply(&mut self.stream, &mut self.logger)?
         };
         for l in lines.iter() {
-            if l.code() == StatusCode::ServiceNotAvailable
-                || l.code() == StatusCode::TransactionFailed
-            {
+            if l.code() == StatusCode::ServiceNotAvailable {
                 self.terminate();
                 return Err(Error::ServerUnavailable);
+            } else if l.code() == StatusCode::TransactionFailed {
+                return Err(Error::Policy);
             }
         }
         Ok(lines)
     }
     pub(crate) fn recv_line(&mut self) -> Result<Line> {
+        let line = self.recv_line_unchecked()?;
+        if line.code() == StatusCode::ServiceNotAvailable {
+            self.terminate();
+            Err(Error::ServerUnavailable)
+        } else if line.code() == StatusCode::TransactionFailed {
+            Err(Error::Policy)
+        } else {
+            Ok(line)
+        }
+    }
+    /// Reads a reply without taking 421 or 554 as an error.
+    fn recv_line_unchecked(&mut self) -> Result<Line> {
         let line = if self.is_tls() {
             let mut tlscon = self.tlscon.take().unwrap();
             let mut tls = rustls::Stream::new(&mut tlscon, &mut self.stream);
@@ -112,63 +146,67 @@ where
         } else {
             stream_recv_line(&mut self.stream, &mut self.logger)?
         };
-        if line.code() == StatusCode::ServiceNotAvailable
-            || line.code() == StatusCode::TransactionFailed
-        {
-            self.terminate();
-            Err(Error::ServerUnavailable)
-        } else {
-            Ok(line)
-        }
+        Ok(line)
     }
     pub(crate) fn write(&mut self, data: &[u8]) -> Result<()> {
         self.logger.client(data);
         if self.is_tls() {
             let mut tlscon = self.tlscon.take().unwrap();
             rustls::Stream::new(&mut tlscon, &mut self.stream)
-                .write(data)
+                .write_all(data)
                 .map_err(|_| Error::Network)?;
             self.tlscon = Some(tlscon);
         } else {
-            self.stream.write(data).map_err(|_| Error::Network)?;
+            self.stream.write_all(data).map_err(|_| Error::Network)?;
         }
         Ok(())
     }
     pub(crate) fn send(&mut self, cmd: Command) -> Result<()> {
         self.write(cmd.to_string().as_bytes())
     }
-    pub(crate) fn set_time_out(&mut self, seconds: u64) -> Result<()> {
+    /// Arms `timeout` for the next reads and writes, cut short by the
+    /// deadline of the mail being sent. Past the deadline the session is
+    /// dropped, since the server may be halfway through the transaction.
+    pub(crate) fn set_time_out(&mut self, timeout: Duration) -> Result<()> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    self.terminate();
+                    return Err(Error::Timeout);
+                }
+                timeout.min(left)
+            }
+            None => timeout,
+        };
         self.stream
-            .set_read_timeout(Some(Duration::new(seconds, 0)))
+            .set_read_timeout(Some(timeout))
             .map_err(|_| Error::Network)?;
         self.stream
-            .set_write_timeout(Some(Duration::new(seconds, 0)))
+            .set_write_timeout(Some(timeout))
             .map_err(|_| Error::Network)?;
         Ok(())
     }
 
-    pub(crate) fn address_resolve(&mut self) -> Result<SocketAddr> {
-        format!("{}:{}", self.server.address, self.server.port)
-            .to_socket_addrs()
-            .map_err(|_| Error::DNS)?
-            .next()
-            .ok_or(Error::DNS)
-    }
-
     pub(crate) fn init_connection(&mut self) -> Result<()> {
-        let address = self.address_resolve()?;
-
-        let client = TcpStream::connect_timeout(&address, Duration::new(self.config.timeout, 0))
-            .map_err(|_| Error::ServerUnreachable)?;
+        let (client, address) =
+            net::connect(&self.config, self.server.address.as_str(), self.server.port)?;
 
         self.stream = client;
-        self.set_time_out(self.config.timeout)?;
+        self.address = address;
+        self.tlscon = None;
+        self.open = true;
+        self.set_time_out(self.config.timeouts.greeting)?;
 
-        let rep = self.recv_line().map_err(|_| Error::InvalidServer)?;
+        // a 421 or a timeout lets the mailer move on to another relay
+        let rep = self.recv_line().map_err(|e| match e {
+            Error::Protocol => Error::InvalidServer,
+            e => e,
+        })?;
         if rep.code() != StatusCode::ServiceReady {
             Err(Error::Protocol)
         } else {
-            Ok(())
+            self.set_time_out(self.config.timeouts.command)
         }
     }
     pub(crate) fn is_tls(&self) -> bool {
@@ -195,14 +233,12 @@ where
                 self.server.meta.pipelining = Support::Supported;
             } else {
                 let words: Vec<&str> = text.split(' ').collect();
-                if words.len() >= 1 {
-                    if words[0] == EhloLine::Auth.to_string() {
-                        for i in 1..words.len() {
-                            if words[i] == AuthMech::Plain.to_string() {
-                                self.server.meta.auth_plain = Support::Supported;
-                            } else if words[i] == AuthMech::Login.to_string() {
-                                self.server.meta.auth_login = Support::Supported;
-                            }
+                if !words.is_empty() && words[0] == EhloLine::Auth.to_string() {
+                    for word in words.iter().skip(1) {
+                        if *word == AuthMech::Plain.to_string() {
+                            self.server.meta.auth_plain = Support::Supported;
+                        } else if *word == AuthMech::Login.to_string() {
+                            self.server.meta.auth_login = Support::Supported;
                         }
                     }
                 }
@@ -246,13 +282,17 @@ where
         self.end()?;
         self.reply_auth_result()
     }
-    pub(crate) fn try_connect(&mut self, credentials: Credentials) -> Result<()> {
+    pub(crate) fn try_connect(&mut self, credentials: Option<Credentials>) -> Result<()> {
         self.init_connection()?;
         self.handshake()?;
         if self.server.meta.tls == Support::Supported {
             self.start_tls()?;
             self.handshake()?;
         }
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return Ok(()),
+        };
         if self.server.meta.auth_plain == Support::Supported {
             self.auth_plain(credentials)?;
         } else if self.server.meta.auth_login == Support::Supported {
@@ -261,6 +301,7 @@ where
         Ok(())
     }
     pub(crate) fn terminate(&mut self) {
+        self.open = false;
         let _ = self.stream.shutdown(std::net::Shutdown::Both);
         self.tlscon.take();
         self.server.meta = ServerMeta::new();
@@ -272,25 +313,42 @@ where
         self.terminate();
         Ok(())
     }
-    pub(crate) fn command_mail_from(&mut self, from: &String) -> Result<()> {
-        self.send(Command::MailFrom(from.clone()))
+    /// Checks the connection is still alive without changing its state.
+    pub fn noop(&mut self) -> Result<()> {
+        self.send(Command::Noop)?;
+        self.recv_line()?.expect(StatusCode::Okay)
+    }
+    /// Drops the current transaction so the next mail starts afresh.
+    pub(crate) fn reset(&mut self) -> Result<()> {
+        self.send(Command::Rset)?;
+        self.recv_line()?.expect(StatusCode::Okay)
+    }
+    pub(crate) fn command_mail_from(&mut self, from: &str) -> Result<()> {
+        self.send(Command::MailFrom(from.to_string()))
     }
     pub(crate) fn reply_mail_from(&mut self, from: &String) -> Result<()> {
-        match self.recv_line()?.code() {
+        let line = self.recv_line()?;
+        match line.code() {
             StatusCode::Okay => Ok(()),
+            StatusCode::LocalError | StatusCode::InsufficientStorage => {
+                Err(Error::Deferred(line.text()))
+            }
             StatusCode::NoAccess => Err(Error::Policy),
             StatusCode::MailBoxNameNotAllowed => Err(Error::MailBoxName(from.to_string())),
             _ => Err(Error::Protocol),
         }
     }
-    pub(crate) fn command_mail_to(&mut self, to: &String) -> Result<()> {
-        self.send(Command::RcptTo(to.clone()))
+    pub(crate) fn command_mail_to(&mut self, to: &str) -> Result<()> {
+        self.send(Command::RcptTo(to.to_string()))
     }
     pub(crate) fn reply_mail_to(&mut self, to: &String) -> Result<()> {
         let line = self.recv_line()?;
         match line.code() {
             StatusCode::Okay | StatusCode::UserNotLocal => Ok(()),
-            StatusCode::NoAccess | StatusCode::MailboxUnavailable => Err(Error::Policy),
+            StatusCode::MailboxUnavailable
+            | StatusCode::LocalError
+            | StatusCode::InsufficientStorage => Err(Error::Deferred(line.text())),
+            StatusCode::NoAccess => Err(Error::Policy),
             StatusCode::MailBoxNameNotAllowed => Err(Error::MailBoxName(to.to_string())),
             StatusCode::UserNotLocalError => Err(Error::Forward(line.text().clone())),
             _ => Err(Error::Protocol),
@@ -300,38 +358,76 @@ where
         self.send(Command::Data)
     }
     pub(crate) fn reply_mail_data(&mut self) -> Result<()> {
-        self.recv_line()?.expect(StatusCode::StartMailInput)
+        self.set_time_out(self.config.timeouts.data_init)?;
+        let line = self.recv_line()?;
+        match line.code() {
+            StatusCode::LocalError | StatusCode::InsufficientStorage => {
+                Err(Error::Deferred(line.text()))
+            }
+            _ => line.expect(StatusCode::StartMailInput),
+        }
     }
-    pub(crate) fn command_mail_payload(&mut self, mail: &Mail) -> Result<()> {
-        if self.server.meta.eight_bit_mime == Support::Supported {
-            self.write(mail.to_bytes()?.as_slice())?;
-        } else {
-            self.write(
-                format!(
-                    "From: {}<{}>\r\n",
-                    mail.from_name.as_ref().unwrap_or(&"".to_string()),
-                    mail.from
-                )
-                .as_bytes(),
-            )?;
-            self.write(
-                format!(
-                    "To: {}<{}>\r\n",
-                    mail.to_name.as_ref().unwrap_or(&"".to_string()),
-                    mail.to
-                )
-                .as_bytes(),
-            )?;
-            self.write(format!("Subject: {}\r\n", mail.subject).as_bytes())?;
-            self.write("\r\n".as_bytes())?;
-            self.write(mail.final_text().as_bytes())?;
+    /// Builds the message for DATA and checks it encodes, which only
+    /// fails for lines too long or 8-bit data the server can't take: the
+    /// message only uses 7-bit transfer encodings.
+    pub(crate) fn mail_payload(&self, mail: &Mail) -> Result<Payload> {
+        let payload = mail.payload()?;
+        let mut encoder = DataEncoder::new(self.eight_bit());
+        let mut scratch = vec![];
+        payload.check(|bytes| {
+            scratch.clear();
+            encoder.encode(bytes, &mut scratch)
+        })?;
+        Ok(payload)
+    }
+    fn eight_bit(&self) -> bool {
+        self.server.meta.eight_bit_mime == Support::Supported
+    }
+    /// Encodes and writes the message a block at a time, reading its
+    /// attachment files as it goes. Failing halfway leaves no way to
+    /// cancel the DATA, so the connection is dropped instead.
+    pub(crate) fn command_mail_payload(&mut self, payload: &Payload) -> Result<()> {
+        let mut encoder = DataEncoder::new(self.eight_bit());
+        let mut block = Vec::with_capacity(DATA_BLOCK_SIZE * 2);
+        let result = payload
+            .write(|chunk| {
+                encoder.encode(chunk, &mut block)?;
+                if block.len() >= DATA_BLOCK_SIZE {
+                    self.write_block(&block)?;
+                    block.clear();
+                }
+                Ok(())
+            })
+            .and_then(|_| {
+                encoder.finish(&mut block);
+                self.write_block(&block)
+            });
+        if result.is_err() {
+            self.terminate();
         }
-        self.write("\r\n.\r\n".as_bytes())
+        result
+    }
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        self.set_time_out(self.config.timeouts.data_block)?;
+        self.write(block)
+    }
+    /// Writes the message and reads the reply to it. Once the final dot
+    /// is out the server may have taken the mail, so losing the session
+    /// then is not an error the mail can be sent again after.
+    fn data(&mut self, payload: &Payload) -> Result<()> {
+        self.command_mail_payload(payload)?;
+        self.reply_mail_payload()
+            .map_err(|e| if e.retriable() { Error::Unconfirmed } else { e })
     }
     pub(crate) fn reply_mail_payload(&mut self) -> Result<()> {
-        match self.recv_line()?.code() {
+        self.set_time_out(self.config.timeouts.data_end)?;
+        let line = self.recv_line()?;
+        match line.code() {
             StatusCode::Okay => Ok(()),
-            StatusCode::NoAccess | StatusCode::MailboxUnavailable => Err(Error::Policy),
+            StatusCode::MailboxUnavailable
+            | StatusCode::LocalError
+            | StatusCode::InsufficientStorage => Err(Error::Deferred(line.text())),
+            StatusCode::NoAccess => Err(Error::Policy),
             _ => Err(Error::Protocol),
         }
     }
@@ -339,79 +435,135 @@ where
     pub(crate) fn try_send_mail(&mut self, mail: &Mail) -> Result<()> {
         check_address(mail.from.as_str())?;
         check_address(mail.to.as_str())?;
-        if mail.attachments.len() > 0 && self.server.meta.eight_bit_mime != Support::Supported {
-            return Err(Error::MIMENotSupported);
-        }
-        if self.config.pipeline && self.server.meta.pipelining == Support::Supported {
-            self.command_mail_from(&mail.from)?;
-            self.command_mail_to(&mail.to)?;
-            self.command_mail_data()?;
-            self.reply_mail_from(&mail.from)?;
-            self.reply_mail_to(&mail.to)?;
-            self.reply_mail_data()?;
-            self.command_mail_payload(&mail)?;
-            self.reply_mail_payload()
-        } else {
-            self.command_mail_from(&mail.from)?;
-            self.reply_mail_from(&mail.from)?;
-            self.command_mail_to(&mail.to)?;
-            self.reply_mail_to(&mail.to)?;
-            self.command_mail_data()?;
-            self.reply_mail_data()?;
-            self.command_mail_payload(&mail)?;
-            self.reply_mail_payload()
+        // built up front so a message that can't be sent fails before MAIL FROM
+        let payload = self.mail_payload(mail)?;
+        let deadline = self.config.timeouts.message.map(|t| Instant::now() + t);
+        self.deadline = deadline;
+        let pipelined = self.config.pipeline && self.server.meta.pipelining == Support::Supported;
+        let result = self
+            .set_time_out(self.config.timeouts.command)
+            .and_then(|_| {
+                if pipelined {
+                    self.transaction_pipelined(mail, &payload)
+                } else {
+                    self.transaction(mail, &payload)
+                }
+            });
+        self.deadline = None;
+        // a read cut short by the deadline fails like any other read
+        let result = match result {
+            Err(Error::Network) if deadline.is_some_and(|d| d <= Instant::now()) => {
+                Err(Error::Timeout)
+            }
+            result => result,
+        };
+        // a refused mail leaves the session usable for the next one, a
+        // transient failure leaves it in no known state
+        if let Err(e) = &result {
+            let lost = e.retriable() || matches!(e, Error::Unconfirmed);
+            if lost || (self.open && self.reset().is_err()) {
+                self.terminate();
+            }
         }
+        let _ = self.set_time_out(self.config.timeouts.command);
+        result
     }
 
-    pub fn connect(&mut self, credentials: Credentials) -> Result<()> {
-        let mut retries = self.config.retries;
-        loop {
-            match self.try_connect(credentials.clone()) {
-                Ok(_) => {
-                    return Ok(());
-                }
-                Err(e) => {
-                    if e.retriable() && retries > 0 {
-                        retries = retries - 1;
-                    } else {
-                        return Err(e);
-                    }
-                }
+    fn transaction(&mut self, mail: &Mail, payload: &Payload) -> Result<()> {
+        self.command_mail_from(&mail.from)?;
+        self.reply_mail_from(&mail.from)?;
+        self.command_mail_to(&mail.to)?;
+        self.reply_mail_to(&mail.to)?;
+        self.command_mail_data()?;
+        self.reply_mail_data()?;
+        self.data(payload)
+    }
+
+    fn transaction_pipelined(&mut self, mail: &Mail, payload: &Payload) -> Result<()> {
+        self.command_mail_from(&mail.from)?;
+        self.command_mail_to(&mail.to)?;
+        self.command_mail_data()?;
+        let from = self.reply_mail_from(&mail.from);
+        let to = self.reply_mail_to(&mail.to);
+        if from.is_err() || to.is_err() {
+            let alive = [&from, &to]
+                .iter()
+                .all(|reply| !matches!(reply, Err(e) if e.retriable()));
+            if alive {
+                // DATA was sent anyway and the server refuses it, often with 554
+                self.recv_line_unchecked()?;
             }
+            return from.and(to);
         }
+        self.reply_mail_data()?;
+        self.data(payload)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Whether the session is still up: a session the server ended or
+    /// that broke halfway through a mail can't be used again.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Connects and authenticates with `credentials`, or stays
+    /// unauthenticated when none are given, as when delivering to an MX.
+    pub fn connect(&mut self, credentials: Option<Credentials>) -> Result<()> {
+        self.credentials = credentials.clone();
+        self.with_retries(|con| con.try_connect(credentials.clone()))
     }
 
+    /// Says QUIT, unless the session has already ended.
     pub fn close(&mut self) -> Result<()> {
-        let mut retries = self.config.retries;
-        loop {
-            match self.try_close() {
-                Ok(_) => {
-                    return Ok(());
-                }
-                Err(e) => {
-                    if e.retriable() && retries > 0 {
-                        retries = retries - 1;
-                    } else {
-                        return Err(e);
-                    }
-                }
-            }
+        if !self.open {
+            return Ok(());
         }
+        self.with_retries(|con| con.try_close())
     }
 
+    /// Sends `mail`, opening the session again first if it has ended,
+    /// as after a 421.
     pub fn send_mail(&mut self, mail: &Mail) -> Result<()> {
-        let mut retries = self.config.retries;
+        self.with_retries(|con| {
+            if !con.open {
+                con.try_connect(con.credentials.clone())?;
+            }
+            con.try_send_mail(mail)
+        })
+    }
+
+    /// Runs `op` until it succeeds, fails with an error that isn't
+    /// retriable, or runs out of retries, waiting between attempts as
+    /// the retry policy says. A cancel during the wait ends it with
+    /// `Error::Cancelled`.
+    fn with_retries<T>(&mut self, mut op: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let mut attempt = 0;
         loop {
-            match self.try_send_mail(&mail) {
-                Ok(_) => {
-                    return Ok(());
+            match op(self) {
+                Ok(value) => {
+                    return Ok(value);
                 }
                 Err(e) => {
-                    if e.retriable() && retries > 0 {
-                        retries = retries - 1;
-                    } else {
+                    attempt += 1;
+                    if !e.retriable() || attempt > self.config.retries {
+                        return Err(e);
+                    }
+                    let delay = self.config.retry.delay(attempt);
+                    if !self.config.retry.allows(start.elapsed(), delay) {
                         return Err(e);
                     }
+                    self.logger.event(Event::Retry {
+                        attempt,
+                        delay,
+                        error: e,
+                    });
+                    if self.cancel.sleep(delay) {
+                        return Err(Error::Cancelled);
+                    }
                 }
             }
         }
@@ -425,3 +577,94 @@ impl<L: Logger> Drop for MailerConnection<L> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, FakeServer, NullLogger};
+    use crate::{CancelToken, Credentials, Error, Mail, Mailer, RetryPolicy};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn mail() -> Mail {
+        Mail {
+            from: "sender@example.com".to_string(),
+            to: "rcpt@example.com".to_string(),
+            subject: "retried".to_string(),
+            text: "hello".to_string(),
+            ..Mail::default()
+        }
+    }
+
+    fn credentials() -> Credentials {
+        Credentials::new("user".to_string(), "secret".to_string())
+    }
+
+    #[test]
+    fn retry_opens_session_again_after_421() {
+        let closed = AtomicBool::new(false);
+        let server = FakeServer::start(move |line| {
+            (line.starts_with("MAIL FROM") && !closed.swap(true, Ordering::SeqCst))
+                .then(|| "421 shutting down".to_string())
+        });
+        let mut config = testing::config();
+        config.retries = 1;
+        config.retry_policy(RetryPolicy::immediate());
+        let mailer = Mailer::new(server.server(), config, NullLogger);
+        let mut con = mailer.connect(credentials()).unwrap();
+        assert!(con.send_mail(&mail()).is_ok());
+        assert_eq!(server.sessions(), 2);
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    fn retrying_mailer(server: &FakeServer) -> Mailer<NullLogger> {
+        let mut config = testing::config();
+        config.retries = 2;
+        config.retry_policy(RetryPolicy::immediate());
+        Mailer::new(server.server(), config, NullLogger)
+    }
+
+    #[test]
+    fn rejected_mail_is_not_sent_again() {
+        let server = FakeServer::start(|line| (line == ".").then(|| "554 spam".to_string()));
+        let mut con = retrying_mailer(&server).connect(credentials()).unwrap();
+        assert!(matches!(con.send_mail(&mail()), Err(Error::Policy)));
+        assert_eq!(server.sessions(), 1);
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    #[test]
+    fn mail_lost_after_final_dot_is_not_sent_again() {
+        let server = FakeServer::start(|line| (line == ".").then(|| "421 going away".to_string()));
+        let mut con = retrying_mailer(&server).connect(credentials()).unwrap();
+        assert!(matches!(con.send_mail(&mail()), Err(Error::Unconfirmed)));
+        assert_eq!(server.sessions(), 1);
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    #[test]
+    fn cancel_ends_wait_between_retries() {
+        let server = FakeServer::start(|line| {
+            line.starts_with("MAIL FROM")
+                .then(|| "421 shutting down".to_string())
+        });
+        let mut config = testing::config();
+        config.retries = 3;
+        config.retry_policy(RetryPolicy {
+            initial_delay: Duration::from_secs(60),
+            jitter: 0.0,
+            ..RetryPolicy::new()
+        });
+        let mailer = Mailer::new(server.server(), config, NullLogger);
+        let mut con = mailer.connect(credentials()).unwrap();
+        let cancel = CancelToken::new();
+        con.cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        let start = Instant::now();
+        assert!(matches!(con.send_mail(&mail()), Err(Error::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
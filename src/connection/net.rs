@@ -0,0 +1,256 @@
+use super::proxy;
+use crate::{Config, Error, Resolver, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for the PTR record of the bind address before
+/// greeting with the address literal instead.
+const REVERSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Orders resolved addresses the way Happy Eyeballs (RFC 8305) does:
+/// families alternate, starting with the family of the first result.
+pub(crate) fn sort_addresses(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = match addresses.first() {
+        Some(address) => address.is_ipv6(),
+        None => return addresses,
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == prefer_v6);
+    let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.drain(..);
+    let mut other = other.drain(..);
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => {
+                sorted.extend(first);
+                sorted.extend(second);
+            }
+        }
+    }
+    sorted
+}
+
+pub(crate) fn address_resolve(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>> {
+    let addresses = resolver.addresses(host, port)?;
+    if addresses.is_empty() {
+        Err(Error::DNS)
+    } else {
+        Ok(sort_addresses(addresses))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+fn bind_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+fn bind_interface(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Connects to `address` from the configured local address and interface.
+fn connect_from(config: &Config, address: &SocketAddr) -> io::Result<TcpStream> {
+    let timeout = config.timeouts.connect;
+    if config.bind_address.is_none() && config.interface.is_none() {
+        return TcpStream::connect_timeout(address, timeout);
+    }
+    let socket = Socket::new(
+        Domain::for_address(*address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if let Some(interface) = config.interface.as_ref() {
+        bind_interface(&socket, interface.as_str())?;
+    }
+    if let Some(local) = config.bind_address {
+        socket.bind(&SocketAddr::new(local, 0).into())?;
+    }
+    socket.connect_timeout(&(*address).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Tries every address in order, giving each one the connect timeout,
+/// and returns the first stream that connects along with its address.
+/// Addresses of a family other than the bind address's are skipped.
+pub(crate) fn connect_any(
+    config: &Config,
+    addresses: &[SocketAddr],
+) -> Result<(TcpStream, SocketAddr)> {
+    let usable = |address: &&SocketAddr| match config.bind_address {
+        Some(local) => local.is_ipv6() == address.is_ipv6(),
+        None => true,
+    };
+    for address in addresses.iter().filter(usable) {
+        if let Ok(stream) = connect_from(config, address) {
+            return Ok((stream, *address));
+        }
+    }
+    Err(Error::ServerUnreachable)
+}
+
+/// The name to greet servers with: the configured one, or else the name
+/// the bind address reverse-resolves to, or its address literal. The
+/// lookup is made once per `Config` and waits at most `REVERSE_TIMEOUT`.
+pub(crate) fn ehlo_name(config: &Config) -> String {
+    if let Some(name) = config.ehlo_name.as_ref() {
+        return name.clone();
+    }
+    let local = match config.bind_address {
+        Some(local) => local,
+        None => return "me".to_string(),
+    };
+    config
+        .ehlo_names
+        .lock()
+        .unwrap()
+        .entry(local)
+        .or_insert_with(|| reverse_name(config, local))
+        .clone()
+}
+
+fn reverse_name(config: &Config, local: IpAddr) -> String {
+    let resolver = config.resolver.clone();
+    let (sender, receiver) = mpsc::channel();
+    // a lookup that outlasts the wait finishes unheard
+    thread::spawn(move || {
+        let _ = sender.send(resolver.reverse(local));
+    });
+    receiver
+        .recv_timeout(REVERSE_TIMEOUT)
+        .ok()
+        .and_then(|names| names.ok())
+        .and_then(|names| names.into_iter().next())
+        .map(|name| name.trim_end_matches('.').to_string())
+        .unwrap_or_else(|| match local {
+            IpAddr::V4(ip) => format!("[{}]", ip),
+            IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+        })
+}
+
+/// Opens a stream to `host` on `port`, tunneled through the configured
+/// proxy if any. With a proxy, `host` is resolved by the proxy and the
+/// returned address is the proxy's.
+pub(crate) fn connect(config: &Config, host: &str, port: u16) -> Result<(TcpStream, SocketAddr)> {
+    let resolver = config.resolver.as_ref();
+    match config.proxy.as_ref() {
+        None => connect_any(config, &address_resolve(resolver, host, port)?),
+        Some(proxy) => {
+            let addresses = address_resolve(resolver, proxy.address(), proxy.port())?;
+            let (mut stream, address) = connect_any(config, &addresses)?;
+            proxy::tunnel(&mut stream, proxy, host, port, config.timeouts.connect)?;
+            Ok((stream, address))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Exchange, StaticResolver};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    /// Answers PTR queries after `delay`, counting them.
+    struct SlowResolver {
+        delay: Duration,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl Resolver for SlowResolver {
+        fn mx(&self, _domain: &str) -> Result<Vec<Exchange>> {
+            Ok(vec![])
+        }
+        fn addresses(&self, _host: &str, _port: u16) -> Result<Vec<SocketAddr>> {
+            Ok(vec![])
+        }
+        fn reverse(&self, _ip: IpAddr) -> Result<Vec<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(self.delay);
+            Ok(vec!["mail.example.com.".to_string()])
+        }
+    }
+
+    fn slow_config(delay: Duration) -> (Config, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut config = Config::new();
+        config
+            .resolver(SlowResolver {
+                delay,
+                lookups: lookups.clone(),
+            })
+            .bind_address("192.0.2.1".parse().unwrap());
+        (config, lookups)
+    }
+
+    #[test]
+    fn sort_addresses_alternates_families() {
+        let addresses = ["[::1]:25", "[::2]:25", "127.0.0.1:25", "127.0.0.2:25"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let sorted: Vec<String> = sort_addresses(addresses)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            sorted,
+            ["[::1]:25", "127.0.0.1:25", "[::2]:25", "127.0.0.2:25"]
+        );
+    }
+
+    #[test]
+    fn ehlo_name_prefers_configured_name() {
+        let mut config = Config::new();
+        config
+            .ehlo_name("client.example.com".to_string())
+            .bind_address("192.0.2.1".parse().unwrap());
+        assert_eq!(ehlo_name(&config), "client.example.com");
+        assert_eq!(ehlo_name(&Config::new()), "me");
+    }
+
+    #[test]
+    fn ehlo_name_uses_ptr_record() {
+        let local: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut resolver = StaticResolver::new();
+        resolver.ptr(local, "mail.example.com.");
+        let mut config = Config::new();
+        config.resolver(resolver).bind_address(local);
+        assert_eq!(ehlo_name(&config), "mail.example.com");
+        let mut config = Config::new();
+        config
+            .resolver(StaticResolver::new())
+            .bind_address("2001:db8::1".parse().unwrap());
+        assert_eq!(ehlo_name(&config), "[IPv6:2001:db8::1]");
+    }
+
+    #[test]
+    fn ehlo_name_is_resolved_once_per_config() {
+        let (config, lookups) = slow_config(Duration::ZERO);
+        let clone = config.clone();
+        assert_eq!(ehlo_name(&config), "mail.example.com");
+        assert_eq!(ehlo_name(&clone), "mail.example.com");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn ehlo_name_falls_back_on_slow_lookup() {
+        let (config, lookups) = slow_config(Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(ehlo_name(&config), "[192.0.2.1]");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(ehlo_name(&config), "[192.0.2.1]");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+}
@@ -32,10 +32,10 @@ where
     }
     pub(crate) fn recv_digit(&mut self) -> Result<u8> {
         let c = self.recv_char()?;
-        if c > '9' || c < '0' {
+        if !c.is_ascii_digit() {
             Err(Error::Protocol)
         } else {
-            Ok((c as u8) - ('0' as u8))
+            Ok((c as u8) - b'0')
         }
     }
     pub(crate) fn expect_char(&mut self, exp: char) -> Result<()> {
@@ -80,7 +80,7 @@ where
             String::new()
         };
         Ok(Line::new(
-            status_code(code).ok_or_else(|| Error::Protocol)?,
+            status_code(code).ok_or(Error::Protocol)?,
             text,
             next == ' ',
         ))
@@ -94,11 +94,10 @@ where
     }
 
     pub(crate) fn new(stream: &'a mut T, logger: &'a mut L) -> Parser<'a, T, L> {
-        let parser = Parser {
+        Parser {
             logger,
             stream,
             next_char: '\0',
-        };
-        parser
+        }
     }
 }
@@ -1,6 +1,7 @@
 use base64::{engine::general_purpose, Engine};
 
 use crate::{Error, Result};
+use std::fmt;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum StatusCode {
@@ -95,10 +96,10 @@ impl Line {
         }
     }
     pub fn code(&self) -> StatusCode {
-        return self.code;
+        self.code
     }
     pub fn text(&self) -> String {
-        return self.text.clone();
+        self.text.clone()
     }
     pub fn last(&self) -> bool {
         self.last
@@ -117,31 +118,31 @@ pub enum AuthMech {
     Login,
 }
 
-impl ToString for AuthMech {
-    fn to_string(&self) -> String {
-        (match self {
+impl fmt::Display for AuthMech {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             AuthMech::Plain => "PLAIN",
             AuthMech::Login => "LOGIN",
         })
-        .to_string()
     }
 }
 
-impl ToString for EhloLine {
-    fn to_string(&self) -> String {
-        (match self {
+impl fmt::Display for EhloLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             EhloLine::Pipelining => "PIPELINING",
             EhloLine::StartTls => "STARTTLS",
             EhloLine::EightBitMIME => "8BITMIME",
             EhloLine::Auth => "AUTH",
         })
-        .to_string()
     }
 }
 
 pub enum Command {
     Ehlo(String),
     Quit,
+    Noop,
+    Rset,
     StartTls,
     MailFrom(String),
     RcptTo(String),
@@ -163,19 +164,20 @@ pub fn get_auth_login(token: &str) -> String {
     general_purpose::STANDARD.encode(token)
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
-        let mut cmd = match self {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cmd = match self {
             Command::Data => "DATA".to_string(),
             Command::Ehlo(me) => format!("EHLO {}", me),
             Command::StartTls => "STARTTLS".to_string(),
             Command::Quit => "QUIT".to_string(),
+            Command::Noop => "NOOP".to_string(),
+            Command::Rset => "RSET".to_string(),
             Command::MailFrom(from) => format!("MAIL FROM:<{}>", from),
             Command::RcptTo(to) => format!("RCPT TO:<{}>", to),
             Command::AuthPlain(un, pw) => format!("AUTH PLAIN {}", get_auth_plain(un, pw)),
             Command::AuthLogin => "AUTH LOGIN".to_string(),
         };
-        cmd.push_str("\r\n");
-        cmd
+        write!(f, "{}\r\n", cmd)
     }
 }
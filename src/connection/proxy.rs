@@ -0,0 +1,166 @@
+use crate::{Credentials, Error, Proxy, Result};
+use base64::{engine::general_purpose, Engine};
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+fn send(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    stream.write_all(data).map_err(|_| Error::Network)
+}
+
+fn recv(stream: &mut TcpStream, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).map_err(|_| Error::Network)?;
+    Ok(buf)
+}
+
+fn socks5_auth(stream: &mut TcpStream, credentials: &Credentials) -> Result<()> {
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    if username.len() > 255 || password.len() > 255 {
+        return Err(Error::Proxy);
+    }
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    send(stream, &request)?;
+    match recv(stream, 2)?[1] {
+        0 => Ok(()),
+        _ => Err(Error::InvalidCred),
+    }
+}
+
+fn socks5(
+    stream: &mut TcpStream,
+    credentials: Option<&Credentials>,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    if credentials.is_some() {
+        send(stream, &[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASS])?;
+    } else {
+        send(stream, &[SOCKS_VERSION, 1, SOCKS_NO_AUTH])?;
+    }
+    let reply = recv(stream, 2)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::Proxy);
+    }
+    match (reply[1], credentials) {
+        (SOCKS_NO_AUTH, _) => (),
+        (SOCKS_USER_PASS, Some(credentials)) => socks5_auth(stream, credentials)?,
+        _ => return Err(Error::Proxy),
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(Error::DNS);
+            }
+            request.push(SOCKS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    send(stream, &request)?;
+
+    let reply = recv(stream, 4)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::Proxy);
+    }
+    // the bound address that follows is of no use to us but must be drained
+    let bound = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => recv(stream, 1)?[0] as usize,
+        _ => return Err(Error::Proxy),
+    };
+    recv(stream, bound + 2)?;
+    match reply[1] {
+        0 => Ok(()),
+        // host or network unreachable, connection refused
+        3..=5 => Err(Error::ServerUnreachable),
+        _ => Err(Error::Proxy),
+    }
+}
+
+fn http_connect(
+    stream: &mut TcpStream,
+    credentials: Option<&Credentials>,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(credentials) = credentials {
+        let token = general_purpose::STANDARD
+            .encode(format!("{}:{}", credentials.username, credentials.password));
+        request.push_str(format!("Proxy-Authorization: Basic {}\r\n", token).as_str());
+    }
+    request.push_str("\r\n");
+    send(stream, request.as_bytes())?;
+
+    // read byte by byte so nothing past the header is taken from the stream
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(Error::Proxy);
+        }
+        response.push(recv(stream, 1)?[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u32>().ok())
+        .ok_or(Error::Proxy)?;
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(Error::InvalidCred),
+        502..=504 => Err(Error::ServerUnreachable),
+        _ => Err(Error::Proxy),
+    }
+}
+
+/// Asks the proxy `stream` is connected to for a tunnel to `host`:`port`.
+pub(crate) fn tunnel(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<()> {
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|_| Error::Network)?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .map_err(|_| Error::Network)?;
+    match proxy {
+        Proxy::Socks5 { credentials, .. } => socks5(stream, credentials.as_ref(), host, port),
+        Proxy::HttpConnect { credentials, .. } => {
+            http_connect(stream, credentials.as_ref(), host, port)
+        }
+    }
+}
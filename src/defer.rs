@@ -0,0 +1,67 @@
+use crate::{CancelToken, RetryPolicy};
+use std::time::{Duration, Instant};
+
+/// A mail waiting for another try after a transient failure.
+pub(crate) struct Deferred {
+    pub(crate) index: usize,
+    attempt: u32,
+    since: Instant,
+    due: Instant,
+}
+
+/// Mails put aside after a 4xx reply, such as greylisting, each due for
+/// another try when the deferral policy says. A mail is dropped from the
+/// queue once its next try would fall past the policy's deadline, counted
+/// from its first deferral.
+pub(crate) struct DeferralQueue {
+    policy: RetryPolicy,
+    items: Vec<Deferred>,
+}
+
+impl DeferralQueue {
+    pub(crate) fn new(policy: RetryPolicy) -> DeferralQueue {
+        DeferralQueue {
+            policy,
+            items: vec![],
+        }
+    }
+
+    /// Queues the mail at `index` for its next try and returns how long
+    /// that is from now, or `None` when it has run out of time.
+    pub(crate) fn defer(&mut self, index: usize, previous: Option<&Deferred>) -> Option<Duration> {
+        let (attempt, since) = match previous {
+            Some(deferred) => (deferred.attempt + 1, deferred.since),
+            None => (1, Instant::now()),
+        };
+        let delay = self.policy.delay(attempt);
+        if !self.policy.allows(since.elapsed(), delay) {
+            return None;
+        }
+        self.items.push(Deferred {
+            index,
+            attempt,
+            since,
+            due: Instant::now() + delay,
+        });
+        Some(delay)
+    }
+
+    /// Waits for the earliest mail to fall due and takes every mail due
+    /// by then; empty once the queue is, or when cancelled while waiting.
+    pub(crate) fn next_batch(&mut self, cancel: &CancelToken) -> Vec<Deferred> {
+        let due = match self.items.iter().map(|deferred| deferred.due).min() {
+            Some(due) => due,
+            None => return vec![],
+        };
+        if cancel.sleep(due.saturating_duration_since(Instant::now())) {
+            return vec![];
+        }
+        let now = Instant::now();
+        let (batch, rest) = self
+            .items
+            .drain(..)
+            .partition::<Vec<_>, _>(|deferred| deferred.due <= now);
+        self.items = rest;
+        batch
+    }
+}
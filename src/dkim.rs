@@ -0,0 +1,624 @@
+use crate::payload::Payload;
+use crate::pem::{self, der, OID_RSA};
+use crate::{Error, Result};
+use base64::{engine::general_purpose, Engine};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair, RsaKeyPair, UnparsedPublicKey},
+};
+use std::sync::Arc;
+
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// Headers signed by default, those of them a mail has. From is always
+/// signed.
+const SIGNED_HEADERS: &[&str] = &[
+    "From",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "To",
+    "Cc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "List-Id",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+    "MIME-Version",
+    "Content-Type",
+];
+
+/// How headers or the body are normalized before they are hashed (RFC
+/// 6376 section 3.4). `Relaxed` survives servers that rewrap headers or
+/// trim trailing whitespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    fn name(&self) -> &'static str {
+        match self {
+            Canonicalization::Simple => "simple",
+            Canonicalization::Relaxed => "relaxed",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Canonicalization> {
+        match name {
+            "simple" => Some(Canonicalization::Simple),
+            "relaxed" => Some(Canonicalization::Relaxed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum SigningKey {
+    Rsa(Arc<RsaKeyPair>),
+    Ed25519(Arc<Ed25519KeyPair>),
+}
+
+/// Signs mails with DKIM (RFC 6376) for `domain`, whose DNS publishes
+/// the public key at `<selector>._domainkey.<domain>`. Keys are RSA,
+/// signed with rsa-sha256, or Ed25519, signed with ed25519-sha256 (RFC
+/// 8463).
+#[derive(Clone)]
+pub struct DkimSigner {
+    pub domain: String,
+    pub selector: String,
+    /// Names of the headers to sign, each signed if the mail has it.
+    pub headers: Vec<String>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    key: SigningKey,
+}
+
+impl DkimSigner {
+    /// Reads the private key from PEM: RSA as PKCS#1 or PKCS#8, Ed25519
+    /// as PKCS#8.
+    pub fn from_pem(domain: String, selector: String, pem: &str) -> Result<DkimSigner> {
+        let key = pem::decode(pem)
+            .into_iter()
+            .find_map(|(label, der)| match label.as_str() {
+                "RSA PRIVATE KEY" => RsaKeyPair::from_der(&der)
+                    .ok()
+                    .map(|key| SigningKey::Rsa(Arc::new(key))),
+                "PRIVATE KEY" => match RsaKeyPair::from_pkcs8(&der) {
+                    Ok(key) => Some(SigningKey::Rsa(Arc::new(key))),
+                    Err(_) => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+                        .ok()
+                        .map(|key| SigningKey::Ed25519(Arc::new(key))),
+                },
+                _ => None,
+            })
+            .ok_or_else(|| Error::Dkim("no RSA or Ed25519 private key found".to_string()))?;
+        Ok(DkimSigner {
+            domain,
+            selector,
+            headers: SIGNED_HEADERS.iter().map(|name| name.to_string()).collect(),
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+            key,
+        })
+    }
+    pub fn headers(&mut self, value: Vec<String>) -> &mut DkimSigner {
+        self.headers = value;
+        self
+    }
+    pub fn canonicalization(
+        &mut self,
+        header: Canonicalization,
+        body: Canonicalization,
+    ) -> &mut DkimSigner {
+        self.header_canonicalization = header;
+        self.body_canonicalization = body;
+        self
+    }
+    /// The public key to publish in DNS as the `p=` of the selector's
+    /// record.
+    pub fn public_key(&self) -> String {
+        let key = match &self.key {
+            SigningKey::Rsa(key) => public_key_info(OID_RSA, key.public_key().as_ref()),
+            SigningKey::Ed25519(key) => key.public_key().as_ref().to_vec(),
+        };
+        general_purpose::STANDARD.encode(key)
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self.key {
+            SigningKey::Rsa(_) => "rsa-sha256",
+            SigningKey::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    /// The DKIM-Signature header of `payload`, stamped with the unix
+    /// time `time`. Attachment files are read once to hash the body.
+    pub(crate) fn sign(&self, payload: &Payload, time: i64) -> Result<String> {
+        let mut header_block = vec![];
+        let mut body = BodyHasher::new(self.body_canonicalization);
+        let mut in_body = false;
+        payload.write(|mut chunk| {
+            while !in_body && !chunk.is_empty() {
+                header_block.push(chunk[0]);
+                chunk = &chunk[1..];
+                in_body = header_block.ends_with(b"\r\n\r\n");
+            }
+            body.update(chunk);
+            Ok(())
+        })?;
+        let fields = parse_fields(header_block.as_slice());
+        let mut names = self.headers.clone();
+        if !names.iter().any(|name| name.eq_ignore_ascii_case("from")) {
+            names.insert(0, "From".to_string());
+        }
+        let (signed, data) = select_headers(&fields, &names, self.header_canonicalization);
+        if !signed.iter().any(|name| name.eq_ignore_ascii_case("from")) {
+            return Err(Error::Dkim("the message has no From header".to_string()));
+        }
+        let tags = [
+            "v=1".to_string(),
+            format!("a={}", self.algorithm()),
+            format!(
+                "c={}/{}",
+                self.header_canonicalization.name(),
+                self.body_canonicalization.name()
+            ),
+            format!("d={}", self.domain),
+            format!("s={}", self.selector),
+            format!("t={}", time),
+            format!("h={}", signed.join(":")),
+            format!(
+                "bh={}",
+                general_purpose::STANDARD.encode(body.finish().as_ref())
+            ),
+            "b=".to_string(),
+        ];
+        let header = format!("DKIM-Signature: {}", tags.join(";\r\n\t"));
+        let mut data = data;
+        data.extend(canonical_field(
+            &header,
+            self.header_canonicalization,
+            false,
+        ));
+        let signature = match &self.key {
+            SigningKey::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+                key.sign(
+                    &signature::RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    &data,
+                    &mut signature,
+                )
+                .map_err(|_| Error::Dkim("signing failed".to_string()))?;
+                signature
+            }
+            SigningKey::Ed25519(key) => {
+                let hash = digest::digest(&digest::SHA256, &data);
+                key.sign(hash.as_ref()).as_ref().to_vec()
+            }
+        };
+        Ok(format!(
+            "{}{}\r\n",
+            header,
+            general_purpose::STANDARD.encode(signature)
+        ))
+    }
+}
+
+#[derive(Clone)]
+enum VerifyingKey {
+    Rsa(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+/// Checks the DKIM signature of a message against a public key given
+/// here rather than looked up in DNS, as tests of signed mail need.
+#[derive(Clone)]
+pub struct DkimVerifier {
+    key: VerifyingKey,
+}
+
+impl DkimVerifier {
+    /// Reads a `PUBLIC KEY` or `RSA PUBLIC KEY` PEM block.
+    pub fn from_pem(pem: &str) -> Result<DkimVerifier> {
+        pem::decode(pem)
+            .into_iter()
+            .find_map(|(label, der)| match label.as_str() {
+                "RSA PUBLIC KEY" => Some(VerifyingKey::Rsa(der)),
+                "PUBLIC KEY" => verifying_key(&der),
+                _ => None,
+            })
+            .map(|key| DkimVerifier { key })
+            .ok_or_else(|| Error::Dkim("no RSA or Ed25519 public key found".to_string()))
+    }
+    /// Reads the TXT record of a selector, such as `v=DKIM1; k=rsa; p=...`.
+    pub fn from_record(record: &str) -> Result<DkimVerifier> {
+        let tags = parse_tags(record);
+        let tag = |name: &str| {
+            tags.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let key = general_purpose::STANDARD
+            .decode(tag("p").unwrap_or(""))
+            .map_err(|_| Error::Dkim("the record's key is not base64".to_string()))?;
+        let key = match tag("k").unwrap_or("rsa") {
+            "ed25519" => VerifyingKey::Ed25519(key),
+            // usually a SubjectPublicKeyInfo, but a bare RSAPublicKey is seen too
+            "rsa" => verifying_key(&key).unwrap_or(VerifyingKey::Rsa(key)),
+            k => return Err(Error::Dkim(format!("unknown key type {}", k))),
+        };
+        Ok(DkimVerifier { key })
+    }
+    /// Checks the first DKIM-Signature of `message`.
+    pub fn verify(&self, message: &[u8]) -> Result<()> {
+        let fail = |reason: &str| Err(Error::Dkim(reason.to_string()));
+        let split = match find(message, b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None => return fail("the message has no body"),
+        };
+        let fields = parse_fields(&message[..split]);
+        let signature = match fields
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("dkim-signature"))
+        {
+            Some((_, field)) => field.clone(),
+            None => return fail("the message is not signed"),
+        };
+        let (_, value) = signature.split_once(':').unwrap_or_default();
+        let tags = parse_tags(value);
+        let tag = |name: &str| {
+            tags.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let (header_canon, body_canon) = match tag("c").unwrap_or("simple").split_once('/') {
+            Some((header, body)) => (
+                Canonicalization::parse(header),
+                Canonicalization::parse(body),
+            ),
+            None => (
+                Canonicalization::parse(tag("c").unwrap_or("simple")),
+                Some(Canonicalization::Simple),
+            ),
+        };
+        let (header_canon, body_canon) = match (header_canon, body_canon) {
+            (Some(header), Some(body)) => (header, body),
+            _ => return fail("unknown canonicalization"),
+        };
+        let mut body = BodyHasher::new(body_canon);
+        body.update(&message[split..]);
+        let body_hash = general_purpose::STANDARD.encode(body.finish().as_ref());
+        if tag("bh") != Some(body_hash.as_str()) {
+            return fail("the body hash doesn't match");
+        }
+        let names = tag("h")
+            .unwrap_or("")
+            .split(':')
+            .map(|name| name.trim().to_string())
+            .collect::<Vec<_>>();
+        let (_, mut data) = select_headers(&fields, &names, header_canon);
+        data.extend(canonical_field(
+            &without_signature(&signature),
+            header_canon,
+            false,
+        ));
+        let signature = general_purpose::STANDARD
+            .decode(tag("b").unwrap_or(""))
+            .map_err(|_| Error::Dkim("the signature is not base64".to_string()))?;
+        let verified = match (&self.key, tag("a")) {
+            (VerifyingKey::Rsa(key), Some("rsa-sha256")) => UnparsedPublicKey::new(
+                &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                key,
+            )
+            .verify(&data, &signature),
+            (VerifyingKey::Ed25519(key), Some("ed25519-sha256")) => {
+                let hash = digest::digest(&digest::SHA256, &data);
+                UnparsedPublicKey::new(&signature::ED25519, key).verify(hash.as_ref(), &signature)
+            }
+            _ => return fail("the algorithm doesn't match the key"),
+        };
+        verified.or_else(|_| fail("the signature doesn't match"))
+    }
+}
+
+fn verifying_key(der: &[u8]) -> Option<VerifyingKey> {
+    match pem::public_key_info(der)? {
+        (OID_RSA, key) => Some(VerifyingKey::Rsa(key.to_vec())),
+        (OID_ED25519, key) => Some(VerifyingKey::Ed25519(key.to_vec())),
+        _ => None,
+    }
+}
+
+/// A SubjectPublicKeyInfo for an RSAPublicKey, as DKIM records carry.
+fn public_key_info(oid: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut algorithm = der(pem::TAG_OID, oid);
+    algorithm.extend([0x05, 0x00]);
+    let mut bits = vec![0];
+    bits.extend(key);
+    let mut info = der(pem::TAG_SEQUENCE, &algorithm);
+    info.extend(der(pem::TAG_BIT_STRING, &bits));
+    der(pem::TAG_SEQUENCE, &info)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The header fields of a header block as (name, whole field) pairs,
+/// with folded lines kept in the field and the final CRLF left out.
+fn parse_fields(block: &[u8]) -> Vec<(String, String)> {
+    let block = String::from_utf8_lossy(block);
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in block.split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, field)) = fields.last_mut() {
+                field.push_str("\r\n");
+                field.push_str(line);
+            }
+        } else if let Some((name, _)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), line.to_string()));
+        }
+    }
+    fields
+}
+
+/// Picks the fields named in `names` from the bottom up, as RFC 6376
+/// section 5.4.2 asks, and returns the names found with the canonical
+/// fields to hash.
+fn select_headers(
+    fields: &[(String, String)],
+    names: &[String],
+    canon: Canonicalization,
+) -> (Vec<String>, Vec<u8>) {
+    let mut used = vec![false; fields.len()];
+    let mut signed = vec![];
+    let mut data = vec![];
+    for name in names {
+        let found = fields
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, (field, _))| !used[*i] && field.eq_ignore_ascii_case(name));
+        if let Some((i, (_, field))) = found {
+            used[i] = true;
+            signed.push(name.clone());
+            data.extend(canonical_field(field, canon, true));
+        }
+    }
+    (signed, data)
+}
+
+fn canonical_field(field: &str, canon: Canonicalization, crlf: bool) -> Vec<u8> {
+    let mut out = match canon {
+        Canonicalization::Simple => field.to_string(),
+        Canonicalization::Relaxed => {
+            let (name, value) = field.split_once(':').unwrap_or((field, ""));
+            let value = value.replace("\r\n", "");
+            format!(
+                "{}:{}",
+                name.trim().to_lowercase(),
+                collapse_whitespace(value.as_str()).trim()
+            )
+        }
+    };
+    if crlf {
+        out.push_str("\r\n");
+    }
+    out.into_bytes()
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut space = false;
+    for c in value.chars() {
+        if c == ' ' || c == '\t' {
+            space = true;
+            continue;
+        }
+        if space {
+            out.push(' ');
+            space = false;
+        }
+        out.push(c);
+    }
+    if space {
+        out.push(' ');
+    }
+    out
+}
+
+/// The DKIM-Signature field with the value of its `b=` tag emptied, as
+/// it was when it was signed.
+fn without_signature(field: &str) -> String {
+    let (name, value) = field.split_once(':').unwrap_or((field, ""));
+    let tags = value
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((key, _)) if key.trim() == "b" => format!("{}=", key),
+            _ => tag.to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("{}:{}", name, tags.join(";"))
+}
+
+/// `tag=value` pairs with whitespace taken out of the values, which the
+/// signature and hashes are folded with.
+fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(key, value)| {
+            let value = value.chars().filter(|c| !c.is_whitespace()).collect();
+            (key.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// Hashes a body as it streams past, canonicalized line by line. Line
+/// ends are read the way the DATA encoder writes them, so a bare CR or
+/// LF ends a line too.
+struct BodyHasher {
+    canon: Canonicalization,
+    context: digest::Context,
+    line: Vec<u8>,
+    last_cr: bool,
+    empty_lines: usize,
+    hashed: bool,
+}
+
+impl BodyHasher {
+    fn new(canon: Canonicalization) -> BodyHasher {
+        BodyHasher {
+            canon,
+            context: digest::Context::new(&digest::SHA256),
+            line: vec![],
+            last_cr: false,
+            empty_lines: 0,
+            hashed: false,
+        }
+    }
+
+    fn update(&mut self, input: &[u8]) {
+        for &b in input {
+            if self.last_cr {
+                self.end_line();
+                if b == b'\n' {
+                    continue;
+                }
+            }
+            match b {
+                b'\r' => self.last_cr = true,
+                b'\n' => self.end_line(),
+                _ => self.line.push(b),
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.last_cr = false;
+        if self.canon == Canonicalization::Relaxed {
+            let mut line = Vec::with_capacity(self.line.len());
+            let mut space = false;
+            for &b in self.line.iter() {
+                if b == b' ' || b == b'\t' {
+                    space = true;
+                    continue;
+                }
+                if space {
+                    line.push(b' ');
+                    space = false;
+                }
+                line.push(b);
+            }
+            self.line = line;
+        }
+        // empty lines only count once something follows them
+        if self.line.is_empty() {
+            self.empty_lines += 1;
+            return;
+        }
+        for _ in 0..self.empty_lines {
+            self.context.update(b"\r\n");
+        }
+        self.empty_lines = 0;
+        self.context.update(&self.line);
+        self.context.update(b"\r\n");
+        self.line.clear();
+        self.hashed = true;
+    }
+
+    fn finish(mut self) -> digest::Digest {
+        if self.last_cr || !self.line.is_empty() {
+            self.end_line();
+        }
+        if !self.hashed && self.canon == Canonicalization::Simple {
+            self.context.update(b"\r\n");
+        }
+        self.context.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mail;
+
+    const RSA_KEY: &str = include_str!("../testdata/dkim-rsa.pem");
+    const ED25519_KEY: &str = include_str!("../testdata/dkim-ed25519.pem");
+    const CANONICALIZATIONS: [Canonicalization; 2] =
+        [Canonicalization::Simple, Canonicalization::Relaxed];
+
+    fn signed(key: &str, header: Canonicalization, body: Canonicalization) -> (Vec<u8>, String) {
+        let mut signer =
+            DkimSigner::from_pem("example.com".to_string(), "mail".to_string(), key).unwrap();
+        signer.canonicalization(header, body);
+        let mail = Mail {
+            from: "sender@example.com".to_string(),
+            to: "rcpt@example.com".to_string(),
+            subject: "Signed  with   DKIM".to_string(),
+            text: "Hello,\r\n\r\n.dotted line  \r\n\r\n\r\n".to_string(),
+            date: Some(1_700_000_000),
+            dkim: Some(signer.clone()),
+            ..Mail::default()
+        };
+        (mail.to_bytes().unwrap(), signer.public_key())
+    }
+
+    fn round_trip(key: &str, k: &str) {
+        for header in CANONICALIZATIONS {
+            for body in CANONICALIZATIONS {
+                let (message, public_key) = signed(key, header, body);
+                let record = format!("v=DKIM1; k={}; p={}", k, public_key);
+                let verifier = DkimVerifier::from_record(record.as_str()).unwrap();
+                assert!(
+                    verifier.verify(&message).is_ok(),
+                    "{:?}/{:?} doesn't verify",
+                    header,
+                    body
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rsa_signature_verifies() {
+        round_trip(RSA_KEY, "rsa");
+    }
+
+    #[test]
+    fn ed25519_signature_verifies() {
+        round_trip(ED25519_KEY, "ed25519");
+    }
+
+    #[test]
+    fn changed_body_fails() {
+        let (mut message, public_key) = signed(
+            RSA_KEY,
+            Canonicalization::Relaxed,
+            Canonicalization::Relaxed,
+        );
+        let at = find(&message, b"Hello").unwrap();
+        message[at] = b'J';
+        let record = format!("v=DKIM1; p={}", public_key);
+        let verifier = DkimVerifier::from_record(record.as_str()).unwrap();
+        assert!(verifier.verify(&message).is_err());
+    }
+
+    #[test]
+    fn relaxed_body_ignores_trailing_whitespace() {
+        let hash = |body: &[u8]| {
+            let mut hasher = BodyHasher::new(Canonicalization::Relaxed);
+            hasher.update(body);
+            hasher.finish().as_ref().to_vec()
+        };
+        assert_eq!(hash(b"a  b \t\r\n\r\n\r\n"), hash(b"a b\r\n"));
+        assert_ne!(hash(b"a b\r\n"), hash(b"ab\r\n"));
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{Error, Result};
+use base64::{engine::general_purpose, Engine};
+
+/// Longest run of UTF-8 bytes put into one encoded-word, which keeps the
+/// word within the 75 characters RFC 2047 allows, and short enough to
+/// follow a name such as `Subject: ` on a 78-character line.
+const WORD_BYTES: usize = 42;
+
+/// Length a header line is folded at, not counting the CRLF (RFC 5322
+/// section 2.1.1).
+const LINE_LENGTH: usize = 78;
+
+/// Headers the message is built with. Giving them again would make a
+/// mail carry two of them or break its MIME structure.
+const RESERVED: &[&str] = &[
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "sender",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "return-path",
+    "received",
+];
+
+/// CR and LF are refused in every header value so a value can never
+/// start a header of its own.
+pub(crate) fn check_header(name: &str, value: &str) -> Result<()> {
+    if value.contains(['\r', '\n']) {
+        Err(Error::Header(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// A header name must be printable ASCII without a colon (RFC 5322
+/// section 2.2) and must not be one the message is built with, including
+/// any `Content-` header.
+pub(crate) fn check_header_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+        return Err(Error::HeaderName(name.to_string()));
+    }
+    let lower = name.to_ascii_lowercase();
+    if RESERVED.contains(&lower.as_str()) || lower.starts_with("content-") {
+        return Err(Error::ReservedHeader(name.to_string()));
+    }
+    Ok(())
+}
+
+/// A message id in the angle brackets RFC 5322 writes it with.
+pub(crate) fn encode_message_id(id: &str) -> String {
+    let id = id.trim();
+    if id.starts_with('<') && id.ends_with('>') {
+        id.to_string()
+    } else {
+        format!("<{}>", id)
+    }
+}
+
+fn is_plain(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b' ' || b == b'\t' || b.is_ascii_graphic())
+}
+
+/// Writes `value` as RFC 2047 encoded-words separated by spaces, never
+/// splitting a character across two words.
+fn encode_words(value: &str) -> String {
+    let mut words = vec![];
+    let mut start = 0;
+    while start < value.len() {
+        let mut end = value.len().min(start + WORD_BYTES);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!(
+            "=?utf-8?B?{}?=",
+            general_purpose::STANDARD.encode(&value[start..end])
+        ));
+        start = end;
+    }
+    words.join(" ")
+}
+
+/// Unstructured text such as a subject: kept as is when it is plain
+/// ASCII, or else encoded.
+pub(crate) fn encode_text(value: &str) -> String {
+    if is_plain(value) {
+        value.to_string()
+    } else {
+        encode_words(value)
+    }
+}
+
+/// `value` folded at its spaces so no line is longer than it must be,
+/// given that the first line already holds `written` characters.
+pub(crate) fn fold(value: &str, written: usize) -> String {
+    let mut folded = String::with_capacity(value.len() + value.len() / 32);
+    let mut length = written;
+    for (i, word) in value.split(' ').enumerate() {
+        if i > 0 {
+            if !word.is_empty() && length + 1 + word.len() > LINE_LENGTH {
+                folded.push_str("\r\n");
+                length = 0;
+            }
+            folded.push(' ');
+            length += 1;
+        }
+        folded.push_str(word);
+        length += word.len();
+    }
+    folded
+}
+
+/// A mailbox with an optional display name. ASCII names are quoted when
+/// they contain specials; other names become encoded-words, which RFC 2047
+/// does not allow inside quotes.
+pub(crate) fn encode_address(name: Option<&str>, address: &str) -> String {
+    let name = match name.map(str::trim).filter(|name| !name.is_empty()) {
+        Some(name) => name,
+        None => return format!("<{}>", address),
+    };
+    if !is_plain(name) {
+        return format!("{} <{}>", encode_words(name), address);
+    }
+    let atext =
+        |c: char| c.is_ascii_alphanumeric() || c == ' ' || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    if name.chars().all(atext) {
+        format!("{} <{}>", name, address)
+    } else {
+        let quoted = name.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\" <{}>", quoted, address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_words(value: &str) -> String {
+        let bytes = value
+            .split_whitespace()
+            .flat_map(|word| {
+                let word = word.strip_prefix("=?utf-8?B?").unwrap();
+                let word = word.strip_suffix("?=").unwrap();
+                general_purpose::STANDARD.decode(word).unwrap()
+            })
+            .collect::<Vec<_>>();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn plain_text_is_kept() {
+        assert_eq!(encode_text("Hello, world!"), "Hello, world!");
+    }
+
+    #[test]
+    fn words_never_split_characters() {
+        let value = "Grüße aus Köln — ".repeat(8);
+        let encoded = encode_text(value.as_str());
+        for word in encoded.split(' ') {
+            assert!(word.len() <= 75, "{} is too long", word);
+        }
+        assert_eq!(decode_words(encoded.as_str()), value);
+    }
+
+    #[test]
+    fn long_values_fold_between_words() {
+        let value = encode_text("Grüße aus Köln — ".repeat(8).as_str());
+        let folded = fold(value.as_str(), "Subject: ".len());
+        let header = format!("Subject: {}", folded);
+        for line in header.split("\r\n") {
+            assert!(line.len() <= LINE_LENGTH, "{} is too long", line);
+        }
+        assert!(header
+            .split("\r\n")
+            .skip(1)
+            .all(|line| line.starts_with(' ')));
+        assert_eq!(folded.replace("\r\n", ""), value);
+    }
+
+    #[test]
+    fn short_values_stay_on_one_line() {
+        assert_eq!(fold("a b  c", 9), "a b  c");
+    }
+
+    #[test]
+    fn display_names_are_quoted_or_encoded() {
+        assert_eq!(encode_address(None, "a@example.com"), "<a@example.com>");
+        assert_eq!(
+            encode_address(Some("Ann Lee"), "a@example.com"),
+            "Ann Lee <a@example.com>"
+        );
+        assert_eq!(
+            encode_address(Some("Lee, \"Ann\""), "a@example.com"),
+            "\"Lee, \\\"Ann\\\"\" <a@example.com>"
+        );
+        let encoded = encode_address(Some("Zoë"), "z@example.com");
+        let (name, address) = encoded.rsplit_once(' ').unwrap();
+        assert_eq!(decode_words(name), "Zoë");
+        assert_eq!(address, "<z@example.com>");
+    }
+
+    #[test]
+    fn header_names_are_checked() {
+        assert!(check_header_name("X-Campaign").is_ok());
+        assert!(matches!(
+            check_header_name("X Campaign"),
+            Err(Error::HeaderName(_))
+        ));
+        assert!(matches!(
+            check_header_name("Content-Type"),
+            Err(Error::ReservedHeader(_))
+        ));
+        assert!(matches!(
+            check_header_name("subject"),
+            Err(Error::ReservedHeader(_))
+        ));
+    }
+}
@@ -0,0 +1,273 @@
+/// Turns an HTML body into plain text for the text/plain alternative:
+/// tags are dropped, block elements start new lines, list items get a
+/// dash, links keep their target after the text and character
+/// references are decoded. Scripts, styles and the head are left out.
+pub(crate) fn html_to_text(html: &str) -> String {
+    let mut text = Text::default();
+    let mut rest = html;
+    let mut skipping: Option<String> = None;
+    let mut links: Vec<Option<String>> = vec![];
+    while !rest.is_empty() {
+        let (before, after) = match rest.find('<') {
+            Some(start) => rest.split_at(start),
+            None => (rest, ""),
+        };
+        if skipping.is_none() {
+            text.push_inline(decode_entities(before).as_str());
+        }
+        if after.is_empty() {
+            break;
+        }
+        if let Some(comment) = after.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let end = match after.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = Tag::parse(&after[1..end]);
+        rest = &after[end + 1..];
+        if let Some(name) = skipping.as_ref() {
+            if tag.closing && tag.name == *name {
+                skipping = None;
+            }
+            continue;
+        }
+        match (tag.name.as_str(), tag.closing) {
+            ("script" | "style" | "head" | "title", false) => skipping = Some(tag.name),
+            ("br", _) => text.line_break(),
+            ("p" | "div" | "table" | "blockquote" | "pre", _) => text.paragraph(),
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", _) => text.paragraph(),
+            ("ul" | "ol", _) => text.paragraph(),
+            ("tr" | "hr", _) => text.line_break(),
+            ("td" | "th", false) => text.push_inline(" "),
+            ("li", false) => {
+                text.line_break();
+                text.push_inline("- ");
+            }
+            ("a", false) => links.push(tag.attribute("href")),
+            ("a", true) => {
+                if let Some(Some(href)) = links.pop() {
+                    if !href.starts_with('#') && !text.ends_with(href.as_str()) {
+                        text.push_inline(format!(" ({})", href).as_str());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    text.finish()
+}
+
+/// Plain text built up with HTML's whitespace rules: runs of spaces and
+/// newlines in the source collapse to a single space, and explicit
+/// breaks never leave more than one blank line.
+#[derive(Default)]
+struct Text {
+    out: String,
+    space: bool,
+}
+
+impl Text {
+    fn push_inline(&mut self, value: &str) {
+        for c in value.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                self.space = true;
+                continue;
+            }
+            if self.space && !self.out.is_empty() && !self.out.ends_with('\n') {
+                self.out.push(' ');
+            }
+            self.space = false;
+            self.out.push(if c == '\u{a0}' { ' ' } else { c });
+        }
+    }
+
+    fn line_break(&mut self) {
+        self.space = false;
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn paragraph(&mut self) {
+        self.line_break();
+        self.line_break();
+    }
+
+    fn ends_with(&self, value: &str) -> bool {
+        self.out.ends_with(value)
+    }
+
+    fn finish(self) -> String {
+        let lines = self.out.lines().map(str::trim_end).collect::<Vec<_>>();
+        let mut out = lines.join("\n").trim().to_string();
+        out.push('\n');
+        out
+    }
+}
+
+struct Tag {
+    name: String,
+    closing: bool,
+    attributes: String,
+}
+
+impl Tag {
+    fn parse(source: &str) -> Tag {
+        let source = source.trim().trim_end_matches('/');
+        let (closing, source) = match source.strip_prefix('/') {
+            Some(source) => (true, source),
+            None => (false, source),
+        };
+        let (name, attributes) = source
+            .split_once(|c: char| c.is_whitespace())
+            .unwrap_or((source, ""));
+        Tag {
+            name: name.to_lowercase(),
+            closing,
+            attributes: attributes.to_string(),
+        }
+    }
+
+    fn attribute(&self, name: &str) -> Option<String> {
+        let mut rest = self.attributes.as_str();
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim().rsplit(char::is_whitespace).next()?;
+            let value = rest[eq + 1..].trim_start();
+            let (value, after) = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let value = &value[1..];
+                    let end = value.find(quote).unwrap_or(value.len());
+                    (&value[..end], &value[(end + 1).min(value.len())..])
+                }
+                _ => {
+                    let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                    (&value[..end], &value[end..])
+                }
+            };
+            if key.eq_ignore_ascii_case(name) {
+                return Some(decode_entities(value));
+            }
+            rest = after;
+        }
+        None
+    }
+}
+
+fn decode_entities(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "euro" => '€',
+        "pound" => '£',
+        "laquo" => '«',
+        "raquo" => '»',
+        "aacute" => 'á',
+        "agrave" => 'à',
+        "auml" => 'ä',
+        "ccedil" => 'ç',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "iacute" => 'í',
+        "ntilde" => 'ñ',
+        "oacute" => 'ó',
+        "ouml" => 'ö',
+        "uacute" => 'ú',
+        "uuml" => 'ü',
+        "szlig" => 'ß',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_and_breaks_become_lines() {
+        let html = "<html><head><title>Hi</title><style>p { color: red }</style></head>\
+                    <body><h1>News</h1>\n<p>First   line<br>second\nline</p>\
+                    <p>Last</p><script>alert(1)</script></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "News\n\nFirst line\nsecond line\n\nLast\n"
+        );
+    }
+
+    #[test]
+    fn lists_get_dashes() {
+        assert_eq!(
+            html_to_text("<p>Menu:</p><ul><li>Tea</li><li>Cake</li></ul>"),
+            "Menu:\n\n- Tea\n- Cake\n"
+        );
+    }
+
+    #[test]
+    fn links_keep_their_target() {
+        assert_eq!(
+            html_to_text(r#"<a href="https://example.com/">Visit us</a>"#),
+            "Visit us (https://example.com/)\n"
+        );
+        assert_eq!(
+            html_to_text(r#"<a href="https://example.com/">https://example.com/</a>"#),
+            "https://example.com/\n"
+        );
+        assert_eq!(html_to_text("<a href='#top'>Top</a>"), "Top\n");
+    }
+
+    #[test]
+    fn entities_are_decoded_and_comments_dropped() {
+        assert_eq!(
+            html_to_text("Fish &amp; chips&nbsp;&#8212; &#x263A; &lt;3<!-- not <b>shown</b> -->"),
+            "Fish & chips \u{2014} \u{263a} <3\n"
+        );
+    }
+}
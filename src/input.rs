@@ -1,18 +1,55 @@
+use base64::{engine::general_purpose, Engine};
+use ring::hmac;
 use serde_derive::Deserialize;
-use smtp::Mail;
+use smtp::{
+    Attachment, Canonicalization, Certificate, DkimSigner, Error, Mail, Smime, SmimeSigner,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::time::Duration;
 
-use crate::{Config, Credentials, Server};
+use crate::{Config, Credentials, Proxy, Relay, RetryPolicy, Server, Timeouts};
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct MailConfig {
     pub retries: Option<u32>,
     pub timeout: Option<u64>,
+    pub timeouts: Option<MailTimeouts>,
     pub parallel: Option<bool>,
     pub logfile: Option<String>,
     pub pipeline: Option<bool>,
+    pub proxy: Option<String>,
     #[serde(rename = "max-channels")]
     pub max_channels: Option<u32>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    #[serde(rename = "ehlo-name")]
+    pub ehlo_name: Option<String>,
+    #[serde(rename = "defer-for")]
+    pub defer_for: Option<u64>,
+    pub spool: Option<String>,
+    #[serde(rename = "message-id-domain")]
+    pub message_id_domain: Option<String>,
+}
+
+/// Per-phase timeouts in seconds, overriding `timeout` for the phases
+/// given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailTimeouts {
+    connect: Option<u64>,
+    greeting: Option<u64>,
+    command: Option<u64>,
+    #[serde(rename = "data-init")]
+    data_init: Option<u64>,
+    #[serde(rename = "data-block")]
+    data_block: Option<u64>,
+    #[serde(rename = "data-end")]
+    data_end: Option<u64>,
+    message: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -20,8 +57,284 @@ pub struct MailConfig {
 pub struct MailEntry {
     to: Vec<(String, String)>,
     subject: String,
-    text: String,
-    attach: Option<Vec<String>>,
+    text: Option<String>,
+    html: Option<String>,
+    #[serde(rename = "html-file")]
+    html_file: Option<String>,
+    attach: Option<Vec<MailAttachment>>,
+    /// Images the HTML shows as `cid:` URLs, as [content-id, path] pairs.
+    inline: Option<Vec<(String, String)>>,
+    /// A [name, address] pair.
+    #[serde(rename = "reply-to")]
+    reply_to: Option<(String, String)>,
+    #[serde(rename = "in-reply-to")]
+    in_reply_to: Option<String>,
+    references: Option<Vec<String>>,
+    #[serde(rename = "list-id")]
+    list_id: Option<String>,
+    headers: Option<BTreeMap<String, String>>,
+    /// Overrides the `[smime]` defaults for this entry.
+    sign: Option<bool>,
+    encrypt: Option<bool>,
+}
+
+/// A path, or a table giving the path with the name and type to send
+/// it under.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum MailAttachment {
+    Path(String),
+    File {
+        path: String,
+        name: Option<String>,
+        #[serde(rename = "type")]
+        content_type: Option<String>,
+    },
+}
+
+impl MailAttachment {
+    fn destruct(&self) -> Attachment {
+        match self {
+            MailAttachment::Path(path) => Attachment::new(path.clone()),
+            MailAttachment::File {
+                path,
+                name,
+                content_type,
+            } => {
+                let mut attachment = Attachment::new(path.clone());
+                if let Some(value) = name {
+                    attachment.filename(value.clone());
+                }
+                if let Some(value) = content_type {
+                    attachment.content_type(value.clone());
+                }
+                attachment
+            }
+        }
+    }
+}
+
+impl MailEntry {
+    /// The HTML body, given inline or read from `html-file`.
+    fn html(&self) -> Option<String> {
+        if let Some(path) = self.html_file.as_ref() {
+            return Some(fs::read_to_string(path).unwrap_or_else(|_| {
+                eprintln!("mail file error: can't read html file: {}", path);
+                std::process::exit(1)
+            }));
+        }
+        self.html.clone()
+    }
+    fn attachments(&self) -> Vec<Attachment> {
+        let attached = self.attach.iter().flatten().map(MailAttachment::destruct);
+        let inline = self
+            .inline
+            .iter()
+            .flatten()
+            .cloned()
+            .map(|(content_id, path)| Attachment::inline(path, content_id));
+        attached.chain(inline).collect()
+    }
+    /// The extra headers every mail of this entry carries.
+    fn headers(&self) -> Vec<(String, String)> {
+        let mut mail = Mail::default();
+        let mut set = || -> Result<(), Error> {
+            if let Some((name, address)) = self.reply_to.as_ref() {
+                mail.reply_to(Some(name.as_str()), address.as_str())?;
+            }
+            if let Some(id) = self.in_reply_to.as_ref() {
+                mail.in_reply_to(id.as_str())?;
+            }
+            if let Some(ids) = self.references.as_ref() {
+                mail.references(ids.as_slice())?;
+            }
+            if let Some(id) = self.list_id.as_ref() {
+                mail.list_id(None, id.as_str())?;
+            }
+            for (name, value) in self.headers.iter().flatten() {
+                mail.header(name.as_str(), value.as_str())?;
+            }
+            Ok(())
+        };
+        if let Err(error) = set() {
+            let reason = match error {
+                Error::Header(name) => format!("{} has a line break", name),
+                Error::HeaderName(name) => format!("\"{}\" is not a header name", name),
+                Error::ReservedHeader(name) => format!("{} can't be set", name),
+                Error::DuplicateHeader(name) => format!("{} is given twice", name),
+                _ => "invalid header".to_string(),
+            };
+            eprintln!("mail file error: [{}] {}", self.subject, reason);
+            std::process::exit(1)
+        }
+        mail.headers
+    }
+}
+
+/// Settings for bulk sends. Each mail gets the `unsubscribe` URIs, in
+/// which `{address}` stands for the recipient's address and `{token}` for
+/// a token of it signed with `secret`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailBulk {
+    unsubscribe: Vec<String>,
+    /// Offers RFC 8058 one-click unsubscription by POST to the `https:`
+    /// URI, which defaults to on.
+    #[serde(rename = "one-click")]
+    one_click: Option<bool>,
+    secret: Option<String>,
+}
+
+impl MailBulk {
+    fn check(&self) {
+        let error = |message: &str| {
+            eprintln!("mail file error: [bulk] {}", message);
+            std::process::exit(1)
+        };
+        for uri in self.unsubscribe.iter() {
+            let lower = uri.to_ascii_lowercase();
+            if !lower.starts_with("https:") && !lower.starts_with("mailto:") {
+                error(format!("unsubscribe uri is neither https nor mailto: {}", uri).as_str());
+            }
+            if uri.contains("{token}") && self.secret.is_none() {
+                error("{token} needs a secret");
+            }
+        }
+    }
+    fn apply(&self, mail: &mut Mail) {
+        let uris = self
+            .unsubscribe
+            .iter()
+            .map(|uri| {
+                let mut uri = uri.replace("{address}", percent_encode(&mail.to).as_str());
+                if let Some(secret) = self.secret.as_ref() {
+                    uri = uri.replace("{token}", unsubscribe_token(secret, &mail.to).as_str());
+                }
+                uri
+            })
+            .collect::<Vec<_>>();
+        if mail
+            .list_unsubscribe(uris.as_slice(), self.one_click.unwrap_or(true))
+            .is_err()
+        {
+            eprintln!(
+                "mail file error: [{}] can't add unsubscribe headers for <{}>",
+                mail.subject, mail.to
+            );
+            std::process::exit(1)
+        }
+    }
+}
+
+/// The address and an HMAC-SHA256 of it under `secret`, both base64url
+/// and joined by a dot, so the unsubscribe page can tell the address
+/// was not made up.
+fn unsubscribe_token(secret: &str, address: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, address.as_bytes());
+    format!(
+        "{}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(address),
+        general_purpose::URL_SAFE_NO_PAD.encode(tag.as_ref())
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(format!("%{:02X}", b).as_str());
+        }
+    }
+    out
+}
+
+/// DKIM signing with the PEM private key at `key`. `canonicalization`
+/// is `header/body`, such as the default `relaxed/relaxed`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailDkim {
+    domain: String,
+    selector: String,
+    key: String,
+    headers: Option<Vec<String>>,
+    canonicalization: Option<String>,
+}
+
+impl MailDkim {
+    fn destruct(self) -> DkimSigner {
+        let error = |message: String| -> ! {
+            eprintln!("mail file error: [dkim] {}", message);
+            std::process::exit(1)
+        };
+        let pem = fs::read_to_string(self.key.as_str())
+            .unwrap_or_else(|_| error(format!("can't read key file: {}", self.key)));
+        let mut signer = DkimSigner::from_pem(self.domain, self.selector, pem.as_str())
+            .unwrap_or_else(|_| error(format!("no usable private key in {}", self.key)));
+        if let Some(value) = self.headers {
+            signer.headers(value);
+        }
+        if let Some(value) = self.canonicalization {
+            let parse = |name: &str| match name {
+                "simple" => Canonicalization::Simple,
+                "relaxed" => Canonicalization::Relaxed,
+                _ => error(format!("unknown canonicalization: {}", value)),
+            };
+            let (header, body) = value.split_once('/').unwrap_or((value.as_str(), "simple"));
+            signer.canonicalization(parse(header), parse(body));
+        }
+        signer
+    }
+}
+
+/// S/MIME with the sender's PEM `certificate`, which intermediates may
+/// follow, and `key`, and the certificate file of each recipient mails
+/// are encrypted to. `sign` and `encrypt` are the defaults of mails.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailSmime {
+    certificate: Option<String>,
+    key: Option<String>,
+    #[serde(default)]
+    sign: bool,
+    #[serde(default)]
+    encrypt: bool,
+    #[serde(default)]
+    recipients: BTreeMap<String, String>,
+}
+
+impl MailSmime {
+    fn destruct(&self, address: &str) -> Smime {
+        let error = |message: String| -> ! {
+            eprintln!("mail file error: [smime] {}", message);
+            std::process::exit(1)
+        };
+        let read = |path: &str| {
+            fs::read_to_string(path).unwrap_or_else(|_| error(format!("can't read {}", path)))
+        };
+        let reason = |e: Error| match e {
+            Error::Smime(reason) => reason,
+            _ => "invalid certificate".to_string(),
+        };
+        let mut smime = Smime::new();
+        match (self.certificate.as_deref(), self.key.as_deref()) {
+            (Some(certificate), Some(key)) => {
+                let signer = SmimeSigner::from_pem(read(certificate).as_str(), read(key).as_str())
+                    .unwrap_or_else(|e| error(format!("{}: {}", certificate, reason(e))));
+                smime.signer(address, signer);
+            }
+            (None, None) => (),
+            _ => error("certificate and key go together".to_string()),
+        }
+        for (recipient, path) in self.recipients.iter() {
+            let certificate = Certificate::from_pem(read(path).as_str())
+                .unwrap_or_else(|e| error(format!("{}: {}", path, reason(e))));
+            smime.certificate(recipient, certificate);
+        }
+        smime
+    }
 }
 
 #[derive(Deserialize)]
@@ -38,6 +351,17 @@ pub struct MailUser {
 pub struct MailServer {
     address: String,
     port: u16,
+    priority: Option<u32>,
+    weight: Option<u32>,
+}
+
+/// A single `[server]` table or several `[[server]]` tables to fail
+/// over between.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum MailServers {
+    One(MailServer),
+    Many(Vec<MailServer>),
 }
 
 impl From<&MailServer> for Server {
@@ -50,8 +374,11 @@ impl From<&MailServer> for Server {
 #[serde(deny_unknown_fields)]
 pub struct MailFile {
     pub user: MailUser,
-    pub server: MailServer,
+    pub server: Option<MailServers>,
     pub config: Option<MailConfig>,
+    pub bulk: Option<MailBulk>,
+    pub dkim: Option<MailDkim>,
+    pub smime: Option<MailSmime>,
     #[serde(rename = "mail")]
     pub mails: Option<Vec<MailEntry>>,
 }
@@ -61,6 +388,32 @@ fn prompt_password(username: &String) -> String {
     rpassword::read_password().unwrap()
 }
 
+/// Parses `socks5://[user:pass@]host:port` or `http://[user:pass@]host:port`.
+fn parse_proxy(url: &str) -> Option<Proxy> {
+    let (scheme, rest) = url.split_once("://")?;
+    let (credentials, authority) = match rest.trim_end_matches('/').rsplit_once('@') {
+        Some((userinfo, authority)) => {
+            let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            (
+                Some(Credentials::new(username.to_string(), password.to_string())),
+                authority,
+            )
+        }
+        None => (None, rest.trim_end_matches('/')),
+    };
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = port.parse().ok()?;
+    match scheme {
+        "socks5" | "socks5h" => Some(Proxy::socks5(host, port, credentials)),
+        "http" => Some(Proxy::http_connect(host, port, credentials)),
+        _ => None,
+    }
+}
+
 impl MailConfig {
     pub fn destruct(self) -> (Config, Option<String>) {
         let mut config = Config::new();
@@ -69,6 +422,9 @@ impl MailConfig {
         if let Some(value) = self.timeout {
             config.timeout(value);
         }
+        if let Some(value) = self.timeouts {
+            config.timeouts(value.destruct(config.timeouts.clone()));
+        }
         if let Some(value) = self.retries {
             config.retires(value);
         }
@@ -81,6 +437,29 @@ impl MailConfig {
         if let Some(value) = self.pipeline {
             config.pipeline(value);
         }
+        if let Some(value) = self.proxy {
+            config.proxy(parse_proxy(value.as_str()).unwrap_or_else(|| {
+                eprintln!("mail file error: invalid proxy url: {}", value);
+                std::process::exit(1)
+            }));
+        }
+        if let Some(value) = self.bind_address {
+            config.bind_address(value);
+        }
+        if let Some(value) = self.interface {
+            config.interface(value);
+        }
+        if let Some(value) = self.ehlo_name {
+            config.ehlo_name(value);
+        }
+        if let Some(value) = self.defer_for {
+            let mut deferral = RetryPolicy::deferral();
+            deferral.deadline(Duration::new(value, 0));
+            config.deferral(deferral);
+        }
+        if let Some(value) = self.message_id_domain {
+            config.message_id_domain(value);
+        }
         if let Some(value) = self.logfile {
             logfile = Some(value);
         }
@@ -89,21 +468,83 @@ impl MailConfig {
     }
 }
 
+impl MailTimeouts {
+    fn destruct(self, mut timeouts: Timeouts) -> Timeouts {
+        let seconds = |value: u64| Duration::new(value, 0);
+        if let Some(value) = self.connect {
+            timeouts.connect(seconds(value));
+        }
+        if let Some(value) = self.greeting {
+            timeouts.greeting(seconds(value));
+        }
+        if let Some(value) = self.command {
+            timeouts.command(seconds(value));
+        }
+        if let Some(value) = self.data_init {
+            timeouts.data_init(seconds(value));
+        }
+        if let Some(value) = self.data_block {
+            timeouts.data_block(seconds(value));
+        }
+        if let Some(value) = self.data_end {
+            timeouts.data_end(seconds(value));
+        }
+        if let Some(value) = self.message {
+            timeouts.message(seconds(value));
+        }
+        timeouts
+    }
+}
+
 impl MailServer {
-    pub fn destruct(self) -> Server {
-        Server::new(self.address, self.port)
+    pub fn destruct(self) -> Relay {
+        let mut relay = Relay::new(Server::new(self.address, self.port));
+        if let Some(value) = self.priority {
+            relay.priority(value);
+        }
+        if let Some(value) = self.weight {
+            relay.weight(value);
+        }
+        relay
+    }
+}
+
+impl MailServers {
+    pub fn destruct(self) -> Vec<Relay> {
+        match self {
+            MailServers::One(server) => vec![server.destruct()],
+            MailServers::Many(servers) => servers.into_iter().map(MailServer::destruct).collect(),
+        }
     }
 }
 
 impl MailFile {
-    pub fn destruct(mut self) -> (Server, Vec<Mail>, Config, Option<String>, Credentials) {
+    /// Without a `[server]` table mails are delivered directly to the
+    /// recipients' MX hosts and no password is needed.
+    pub fn destruct(
+        mut self,
+    ) -> (
+        Option<Vec<Relay>>,
+        Vec<Mail>,
+        Config,
+        Option<String>,
+        Credentials,
+    ) {
         let mut mails = vec![];
 
-        let (config, logfile) = if let Some(cfg) = self.config.take() {
+        let (mut config, logfile) = if let Some(cfg) = self.config.take() {
             cfg.destruct()
         } else {
             (Config::new(), None)
         };
+        if let Some(dkim) = self.dkim.take() {
+            config.dkim(dkim.destruct());
+        }
+        let smime = self.smime.as_ref().map(|smime| {
+            let keys = smime.destruct(self.user.address.as_str());
+            config.smime(keys.clone());
+            (smime, keys)
+        });
 
         let username = self
             .user
@@ -111,26 +552,69 @@ impl MailFile {
             .clone()
             .unwrap_or(self.user.address.clone());
 
-        let password = self
-            .user
-            .password
-            .clone()
-            .unwrap_or_else(|| prompt_password(&username));
+        let server = self.server.take().map(MailServers::destruct);
+        if let Some(bulk) = self.bulk.as_ref() {
+            bulk.check();
+        }
 
-        let server = self.server.destruct();
+        let password = match (self.user.password.clone(), &server) {
+            (Some(password), _) => password,
+            (None, Some(_)) => prompt_password(&username),
+            (None, None) => String::new(),
+        };
 
         if let Some(mut file_mails) = self.mails.take() {
             for m in file_mails.drain(..) {
+                let html = m.html();
+                let headers = m.headers();
+                if m.text.is_none() && html.is_none() {
+                    eprintln!("mail file error: [{}] has neither text nor html", m.subject);
+                    std::process::exit(1)
+                }
                 for (to_name, to) in m.to.iter().cloned() {
-                    let mail = Mail {
+                    let mut mail = Mail {
+                        id: Mail::new_id(),
                         from: self.user.address.clone(),
                         from_name: self.user.name.clone(),
                         to,
                         to_name: Some(to_name),
                         subject: m.subject.clone(),
-                        text: m.text.clone(),
-                        attachments: m.attach.clone().unwrap_or(vec![]),
+                        text: m.text.clone().unwrap_or_default(),
+                        html: html.clone(),
+                        attachments: m.attachments(),
+                        headers: headers.clone(),
+                        message_id: None,
+                        date: None,
+                        fixed_boundaries: false,
+                        dkim: None,
+                        sign: false,
+                        encrypt: false,
+                        smime: None,
                     };
+                    if let Some((defaults, keys)) = smime.as_ref() {
+                        mail.sign = m.sign.unwrap_or(defaults.sign);
+                        mail.encrypt = m.encrypt.unwrap_or(defaults.encrypt);
+                        if mail.sign && !keys.has_signer(mail.from.as_str()) {
+                            eprintln!(
+                                "mail file error: [{}] signing needs the [smime] certificate and key",
+                                m.subject
+                            );
+                            std::process::exit(1)
+                        }
+                        if mail.encrypt && !keys.has_certificate(mail.to.as_str()) {
+                            eprintln!(
+                                "mail file error: [{}] no [smime] certificate for {}",
+                                m.subject, mail.to
+                            );
+                            std::process::exit(1)
+                        }
+                    } else if m.sign == Some(true) || m.encrypt == Some(true) {
+                        eprintln!("mail file error: [{}] S/MIME needs [smime]", m.subject);
+                        std::process::exit(1)
+                    }
+                    if let Some(bulk) = self.bulk.as_ref() {
+                        bulk.apply(&mut mail);
+                    }
                     mails.push(mail);
                 }
             }
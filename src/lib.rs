@@ -1,31 +1,75 @@
+mod cancel;
 mod connection;
+mod defer;
+mod dkim;
+mod header;
+mod html;
 mod message;
+mod payload;
+mod pem;
+mod pool;
+mod relay;
+mod resolver;
+mod retry;
+mod smime;
+mod spool;
+#[cfg(test)]
+mod testing;
+mod timeouts;
+mod transport;
+use defer::{DeferralQueue, Deferred};
+use payload::Payload;
+use relay::Relays;
+use spool::SpoolBatch;
 use std::{
     cmp::min,
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+pub use cancel::CancelToken;
 use connection::MailerConnection;
-pub use message::Mail;
+pub use dkim::{Canonicalization, DkimSigner, DkimVerifier};
+pub use message::{Attachment, Content, Mail};
+pub use pool::{MailerPool, PoolConfig, PooledConnection};
+pub use relay::Relay;
+pub use resolver::{Exchange, Resolver, StaticResolver, SystemResolver, MX_PORT};
+pub use retry::RetryPolicy;
+pub use smime::{Certificate, Smime, SmimeSigner};
+pub use spool::{Spool, SpoolState};
+pub use timeouts::Timeouts;
+pub use transport::{
+    Envelope, FileTransport, MemoryTransport, SendmailTransport, SentMail, Transport,
+};
 
 pub enum Event {
-    Connected,
+    Connected(SocketAddr),
     FailedToConnect(Error),
     Disconnencted,
     FailToDisconnect(Error),
-    Retry,
+    Retry {
+        attempt: u32,
+        delay: Duration,
+        error: Error,
+    },
     MailSent {
         subject: String,
         to: String,
+        message_id: String,
     },
     FailedToSendMail {
         subject: String,
         to: String,
         error: Error,
     },
+    MailDeferred {
+        subject: String,
+        to: String,
+        delay: Duration,
+    },
 }
 
 pub trait Logger: Clone + Send + Sync {
@@ -46,20 +90,41 @@ pub enum Error {
     InvalidCred,
     Policy,
     MIMENotSupported,
+    LineTooLong,
     DNS,
+    Proxy,
     MailBoxName(String),
     Forward(String),
     File(String),
+    Transport(String),
+    Header(String),
+    HeaderName(String),
+    ReservedHeader(String),
+    DuplicateHeader(String),
+    Deferred(String),
+    NotAttempted,
+    Cancelled,
+    Timeout,
+    Unconfirmed,
+    Dkim(String),
+    Smime(String),
 }
 
 impl Error {
     pub fn retriable(&self) -> bool {
-        match self {
-            Error::Network | Error::DNS | Error::ServerUnavailable | Error::ServerUnreachable => {
-                true
-            }
-            _ => false,
-        }
+        matches!(
+            self,
+            Error::Network
+                | Error::Timeout
+                | Error::DNS
+                | Error::ServerUnavailable
+                | Error::ServerUnreachable
+        )
+    }
+    /// Whether the server asked for the mail to be sent again later,
+    /// with a 4xx reply to the mail itself.
+    pub fn deferrable(&self) -> bool {
+        matches!(self, Error::Deferred(_))
     }
 }
 
@@ -84,51 +149,195 @@ pub struct Server {
     meta: ServerMeta,
 }
 
+/// A proxy every connection is tunneled through. Server names are sent
+/// to the proxy unresolved, so DNS happens on the proxy's side.
+#[derive(Clone)]
+pub enum Proxy {
+    Socks5 {
+        address: String,
+        port: u16,
+        credentials: Option<Credentials>,
+    },
+    HttpConnect {
+        address: String,
+        port: u16,
+        credentials: Option<Credentials>,
+    },
+}
+
+impl Proxy {
+    pub fn socks5(address: String, port: u16, credentials: Option<Credentials>) -> Proxy {
+        Proxy::Socks5 {
+            address,
+            port,
+            credentials,
+        }
+    }
+    pub fn http_connect(address: String, port: u16, credentials: Option<Credentials>) -> Proxy {
+        Proxy::HttpConnect {
+            address,
+            port,
+            credentials,
+        }
+    }
+    pub(crate) fn address(&self) -> &str {
+        match self {
+            Proxy::Socks5 { address, .. } | Proxy::HttpConnect { address, .. } => address,
+        }
+    }
+    pub(crate) fn port(&self) -> u16 {
+        match self {
+            Proxy::Socks5 { port, .. } | Proxy::HttpConnect { port, .. } => *port,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub retries: u32,
-    pub timeout: u64,
+    pub retry: RetryPolicy,
+    pub deferral: RetryPolicy,
+    pub timeouts: Timeouts,
     pub parallel: bool,
     pub max_channels: u32,
     pub auto_quit: bool,
     pub pipeline: bool,
+    pub resolver: Arc<dyn Resolver>,
+    pub proxy: Option<Proxy>,
+    pub bind_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub ehlo_name: Option<String>,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: u64,
+    pub message_id_domain: Option<String>,
+    pub clock: Option<i64>,
+    pub dkim: Option<DkimSigner>,
+    pub smime: Option<Smime>,
+    /// Names bind addresses reverse-resolved to, shared by the clones
+    /// of a `Config` so the lookup is made once.
+    pub(crate) ehlo_names: Arc<Mutex<HashMap<IpAddr, String>>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             retries: 0,
-            timeout: 5,
+            retry: RetryPolicy::new(),
+            deferral: RetryPolicy::deferral(),
+            timeouts: Timeouts::new(),
             parallel: false,
             max_channels: 8,
             auto_quit: false,
             pipeline: true,
+            resolver: Arc::new(SystemResolver::new()),
+            proxy: None,
+            bind_address: None,
+            interface: None,
+            ehlo_name: None,
+            breaker_threshold: 3,
+            breaker_cooldown: 60,
+            message_id_domain: None,
+            clock: None,
+            dkim: None,
+            smime: None,
+            ehlo_names: Arc::default(),
         }
     }
-    pub fn retires<'a>(&'a mut self, value: u32) -> &'a mut Config {
+    pub fn retires(&mut self, value: u32) -> &mut Config {
         self.retries = value;
         self
     }
-    pub fn timeout<'a>(&'a mut self, value: u64) -> &'a mut Config {
-        self.timeout = value;
+    pub fn retry_policy(&mut self, value: RetryPolicy) -> &mut Config {
+        self.retry = value;
+        self
+    }
+    pub fn deferral(&mut self, value: RetryPolicy) -> &mut Config {
+        self.deferral = value;
         self
     }
-    pub fn parallel<'a>(&'a mut self, value: bool) -> &'a mut Config {
+    /// Uses `value` seconds for every phase of the session.
+    pub fn timeout(&mut self, value: u64) -> &mut Config {
+        self.timeouts = Timeouts::uniform(Duration::new(value, 0));
+        self
+    }
+    pub fn timeouts(&mut self, value: Timeouts) -> &mut Config {
+        self.timeouts = value;
+        self
+    }
+    pub fn parallel(&mut self, value: bool) -> &mut Config {
         self.parallel = value;
         self
     }
-    pub fn max_channels<'a>(&'a mut self, value: u32) -> &'a mut Config {
+    pub fn max_channels(&mut self, value: u32) -> &mut Config {
         self.max_channels = value;
         self
     }
-    pub fn auto_quit<'a>(&'a mut self, value: bool) -> &'a mut Config {
+    pub fn auto_quit(&mut self, value: bool) -> &mut Config {
         self.auto_quit = value;
         self
     }
-    pub fn pipeline<'a>(&'a mut self, value: bool) -> &'a mut Config {
+    pub fn pipeline(&mut self, value: bool) -> &mut Config {
         self.pipeline = value;
         self
     }
+    pub fn resolver(&mut self, value: impl Resolver + 'static) -> &mut Config {
+        self.resolver = Arc::new(value);
+        self.ehlo_names = Arc::default();
+        self
+    }
+    pub fn proxy(&mut self, value: Proxy) -> &mut Config {
+        self.proxy = Some(value);
+        self
+    }
+    pub fn bind_address(&mut self, value: IpAddr) -> &mut Config {
+        self.bind_address = Some(value);
+        self
+    }
+    pub fn interface(&mut self, value: String) -> &mut Config {
+        self.interface = Some(value);
+        self
+    }
+    pub fn ehlo_name(&mut self, value: String) -> &mut Config {
+        self.ehlo_name = Some(value);
+        self
+    }
+    pub fn breaker_threshold(&mut self, value: u32) -> &mut Config {
+        self.breaker_threshold = value;
+        self
+    }
+    pub fn breaker_cooldown(&mut self, value: u64) -> &mut Config {
+        self.breaker_cooldown = value;
+        self
+    }
+    /// The domain of generated Message-IDs, the sender's by default.
+    pub fn message_id_domain(&mut self, value: String) -> &mut Config {
+        self.message_id_domain = Some(value);
+        self
+    }
+    /// Dates every mail at the unix time `clock` and numbers its MIME
+    /// boundaries, so a mail always builds to the same bytes. Meant for
+    /// golden tests.
+    pub fn deterministic(&mut self, clock: i64) -> &mut Config {
+        self.clock = Some(clock);
+        self
+    }
+    /// Signs every mail with DKIM.
+    pub fn dkim(&mut self, value: DkimSigner) -> &mut Config {
+        self.dkim = Some(value);
+        self
+    }
+    /// The S/MIME signers and certificates of mails that ask to be
+    /// signed or encrypted.
+    pub fn smime(&mut self, value: Smime) -> &mut Config {
+        self.smime = Some(value);
+        self
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -150,7 +359,7 @@ struct ServerMeta {
 impl Server {
     pub fn new(address: String, port: u16) -> Server {
         Server {
-            address: address,
+            address,
             port,
             meta: ServerMeta::new(),
         }
@@ -185,150 +394,328 @@ where
     L: Logger + 'static,
 {
     config: Config,
-    server: Server,
+    relays: Option<Arc<Relays>>,
+    transport: Option<Arc<Mutex<Box<dyn Transport>>>>,
+    spool: Option<Arc<SpoolBatch>>,
+    cancel: CancelToken,
     logger: L,
 }
 
+/// A mail along with its index in the post it belongs to.
+type IndexedMail = (usize, Mail);
+
+/// A mail waiting for a channel: its position in the queue's results,
+/// whether it was put back after failing once, and the mail.
+type QueuedMail = (usize, bool, IndexedMail);
+
+/// A transport shared by all channels of a `Mailer`, locked per mail.
+struct SharedTransport(Arc<Mutex<Box<dyn Transport>>>);
+
+impl Transport for SharedTransport {
+    fn send(&mut self, mail: &Mail) -> Result<()> {
+        self.0.lock().unwrap().send(mail)
+    }
+}
+
 impl<L> Mailer<L>
 where
     L: Logger,
 {
     pub fn new(server: Server, config: Config, logger: L) -> Mailer<L> {
+        Mailer::with_relays(vec![Relay::new(server)], config, logger)
+    }
+
+    /// Creates a mailer that sends through `relays`, failing over to the
+    /// next one when a relay can't be reached or answers 421.
+    pub fn with_relays(relays: Vec<Relay>, config: Config, logger: L) -> Mailer<L> {
+        let relays = Relays::new(
+            relays,
+            config.breaker_threshold,
+            Duration::new(config.breaker_cooldown, 0),
+        );
+        Mailer {
+            relays: Some(Arc::new(relays)),
+            transport: None,
+            spool: None,
+            cancel: CancelToken::new(),
+            config,
+            logger,
+        }
+    }
+
+    /// Creates a mailer without a relay that delivers every mail straight
+    /// to the MX hosts of its recipient's domain.
+    pub fn direct(config: Config, logger: L) -> Mailer<L> {
+        Mailer {
+            relays: None,
+            transport: None,
+            spool: None,
+            cancel: CancelToken::new(),
+            config,
+            logger,
+        }
+    }
+
+    /// Creates a mailer that hands every mail to `transport` instead of
+    /// connecting to a server itself.
+    pub fn with_transport(
+        transport: impl Transport + 'static,
+        config: Config,
+        logger: L,
+    ) -> Mailer<L> {
         Mailer {
-            server,
+            relays: None,
+            transport: Some(Arc::new(Mutex::new(Box::new(transport)))),
+            spool: None,
+            cancel: CancelToken::new(),
             config,
             logger,
         }
     }
 
-    fn address_resolve(&self) -> Result<SocketAddr> {
-        format!("{}:{}", self.server.address, self.server.port)
-            .to_socket_addrs()
-            .map_err(|_| Error::DNS)?
-            .next()
-            .ok_or(Error::DNS)
+    fn with_server(&self, server: Server) -> Mailer<L> {
+        let relays = Relays::new(vec![Relay::new(server)], u32::MAX, Duration::ZERO);
+        Mailer {
+            relays: Some(Arc::new(relays)),
+            transport: None,
+            spool: self.spool.clone(),
+            cancel: self.cancel.clone(),
+            config: self.config.clone(),
+            logger: self.logger.clone(),
+        }
     }
 
     pub fn connect(&self, credentials: Credentials) -> Result<MailerConnection<L>> {
-        let address = self.address_resolve()?;
-        let client = TcpStream::connect_timeout(&address, Duration::new(self.config.timeout, 0))
-            .map_err(|_| Error::ServerUnreachable)?;
+        self.open(Some(credentials))
+    }
+
+    /// Connects to the first relay in order that takes the connection.
+    fn open(&self, credentials: Option<Credentials>) -> Result<MailerConnection<L>> {
+        let relays = self.relays.as_ref().ok_or(Error::InvalidServer)?;
+        let mut error = Error::ServerUnreachable;
+        for relay in relays.order() {
+            match self.open_server(relays.server(relay), credentials.clone()) {
+                Ok(con) => {
+                    relays.succeeded(relay);
+                    return Ok(con);
+                }
+                Err(e) if e.retriable() => {
+                    relays.failed(relay);
+                    error = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error)
+    }
+
+    fn open_server(
+        &self,
+        server: &Server,
+        credentials: Option<Credentials>,
+    ) -> Result<MailerConnection<L>> {
+        let (client, address) =
+            connection::net::connect(&self.config, server.address.as_str(), server.port)?;
 
         let mut mailer = MailerConnection::new(
-            self.server.clone(),
+            server.clone(),
             self.config.clone(),
             client,
+            address,
             self.logger.clone(),
         );
-
+        mailer.cancel = self.cancel.clone();
         mailer.connect(credentials)?;
         Ok(mailer)
     }
 
-    fn post_serial(&self, credentials: Credentials, mails: Vec<Mail>) -> Result<Vec<Result<()>>> {
-        let mut con = match self.connect(credentials) {
-            Ok(con) => con,
-            Err(e) => {
-                self.logger.event(Event::FailedToConnect(e.clone()));
-                return Err(e);
+    /// Opens the transport one channel sends its mails through: the
+    /// mailer's own transport if it has one, or else a new connection.
+    fn open_transport(&self, credentials: Option<Credentials>) -> Result<Box<dyn Transport>> {
+        match self.transport.as_ref() {
+            Some(transport) => Ok(Box::new(SharedTransport(transport.clone()))),
+            None => {
+                let con = self.open(credentials)?;
+                self.logger.event(Event::Connected(con.address()));
+                Ok(Box::new(con))
             }
-        };
-        self.logger.event(Event::Connected);
-        let mut mails = mails;
-        let results = mails
-            .drain(..)
-            .map(|mail| {
-                if let Err(e) = con.send_mail(&mail) {
-                    self.logger.event(Event::FailedToSendMail {
-                        subject: mail.subject.clone(),
-                        to: mail.to.clone(),
-                        error: e.clone(),
-                    });
-                    Err(e)
-                } else {
-                    self.logger.event(Event::MailSent {
-                        subject: mail.subject.clone(),
-                        to: mail.to.clone(),
-                    });
-                    Ok(())
-                }
-            })
-            .collect::<Vec<_>>();
-        match con.close() {
+        }
+    }
+
+    fn close_transport(&self, mut transport: Box<dyn Transport>) {
+        let result = transport.close();
+        if self.transport.is_some() {
+            return;
+        }
+        match result {
             Ok(_) => {
                 self.logger.event(Event::Disconnencted);
-                Ok(results)
             }
             Err(e) => {
                 self.logger.event(Event::FailToDisconnect(e));
-                Ok(results)
             }
         }
     }
 
+    /// Gives `mail` the Message-ID, Date and DKIM signer `Config` asks
+    /// for, and in deterministic mode fixed boundaries, keeping what it
+    /// already has.
+    fn stamp(&self, mail: &mut Mail) {
+        if mail.message_id.is_none() {
+            let domain = self.config.message_id_domain.as_deref();
+            mail.message_id = Some(mail.new_message_id(domain));
+        }
+        if mail.dkim.is_none() {
+            mail.dkim = self.config.dkim.clone();
+        }
+        if mail.smime.is_none() {
+            mail.smime = self.config.smime.clone();
+        }
+        mail.date.get_or_insert_with(|| {
+            self.config.clock.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0)
+            })
+        });
+        if self.config.clock.is_some() {
+            mail.fixed_boundaries = true;
+        }
+    }
+
+    /// Sends `mail` and returns its Message-ID.
+    fn deliver(&self, transport: &mut dyn Transport, mail: &Mail) -> Result<String> {
+        let mut mail = mail.clone();
+        self.stamp(&mut mail);
+        if let Err(e) = transport.send(&mail) {
+            self.logger.event(Event::FailedToSendMail {
+                subject: mail.subject.clone(),
+                to: mail.to.clone(),
+                error: e.clone(),
+            });
+            Err(e)
+        } else {
+            let message_id = mail.message_id.unwrap_or_default();
+            self.logger.event(Event::MailSent {
+                subject: mail.subject,
+                to: mail.to,
+                message_id: message_id.clone(),
+            });
+            Ok(message_id)
+        }
+    }
+
+    /// Sends the mail at `index` of the post, keeping its spool entry,
+    /// if any, up to date. A mail that can't be marked as sending isn't
+    /// sent, so the spool never loses track of it, and the entry keeps
+    /// the Message-ID and Date it was first sent with.
+    fn deliver_indexed(
+        &self,
+        transport: &mut dyn Transport,
+        index: usize,
+        mail: &Mail,
+    ) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let mut mail = mail.clone();
+        self.stamp(&mut mail);
+        if let Some(batch) = self.spool.as_ref() {
+            batch.sending(index, &mail)?;
+        }
+        let result = self.deliver(transport, &mail).map(|_| ());
+        if let Some(batch) = self.spool.as_ref() {
+            // a mail whose outcome can't be recorded would be sent again on resume
+            batch.record(index, &result)?;
+        }
+        result
+    }
+
+    fn post_serial(
+        &self,
+        credentials: Option<Credentials>,
+        mails: Vec<IndexedMail>,
+    ) -> Result<Vec<Result<()>>> {
+        let mut con = match self.open_transport(credentials) {
+            Ok(con) => con,
+            Err(e) => {
+                self.logger.event(Event::FailedToConnect(e.clone()));
+                return Err(e);
+            }
+        };
+        let results = mails
+            .iter()
+            .map(|(index, mail)| self.deliver_indexed(con.as_mut(), *index, mail))
+            .collect::<Vec<_>>();
+        self.close_transport(con);
+        Ok(results)
+    }
+
+    /// Sends mails from the shared queue until it is empty. A mail that
+    /// fails for a transient reason is put back once for any channel to
+    /// try again, the second time its error stands; either way the channel
+    /// goes on with the next mail. Only mails left when no channel could
+    /// connect are `Error::NotAttempted`.
     fn post_channel(
         &mut self,
-        credentials: Credentials,
-        mails: Arc<Mutex<Vec<Mail>>>,
+        credentials: Option<Credentials>,
+        queue: Arc<Mutex<VecDeque<QueuedMail>>>,
         results: Arc<Mutex<Vec<Result<()>>>>,
     ) -> bool {
-        let mut con = match self.connect(credentials) {
+        let mut con = match self.open_transport(credentials) {
             Ok(con) => con,
             Err(e) => {
                 self.logger.event(Event::FailedToConnect(e));
                 return false;
             }
         };
-        self.logger.event(Event::Connected);
         loop {
-            let mut guard = mails.lock().unwrap();
-            let m = guard.pop();
-            let idx = guard.len();
-            drop(guard);
-            match m {
-                Some(mail) => {
-                    if let Err(e) = con.send_mail(&mail) {
-                        self.logger.event(Event::FailedToSendMail {
-                            subject: mail.subject.clone(),
-                            to: mail.to.clone(),
-                            error: e.clone(),
-                        });
-                        results.lock().unwrap()[idx] = Err(e);
-                    } else {
-                        self.logger.event(Event::MailSent {
-                            subject: mail.subject.clone(),
-                            to: mail.to.clone(),
-                        });
-                    }
-                }
+            let next = queue.lock().unwrap().pop_front();
+            let (position, requeued, (index, mail)) = match next {
+                Some(next) => next,
                 None => break,
+            };
+            let result = self.deliver_indexed(con.as_mut(), index, &mail);
+            let again = !requeued && matches!(&result, Err(e) if e.retriable());
+            results.lock().unwrap()[position] = result;
+            if again {
+                queue
+                    .lock()
+                    .unwrap()
+                    .push_back((position, true, (index, mail)));
             }
         }
-        match con.close() {
-            Ok(_) => {
-                self.logger.event(Event::Disconnencted);
-            }
-            Err(e) => {
-                self.logger.event(Event::FailToDisconnect(e));
-            }
-        }
-        return true;
+        self.close_transport(con);
+        true
     }
 
-    fn post_parallel(&self, credentials: Credentials, mails: Vec<Mail>) -> Result<Vec<Result<()>>> {
+    fn post_parallel(
+        &self,
+        credentials: Option<Credentials>,
+        mails: Vec<IndexedMail>,
+    ) -> Result<Vec<Result<()>>> {
         let mail_count = mails.len();
         let thread_count = min(self.config.max_channels, mail_count as u32);
-        let mails = Arc::new(Mutex::new(mails));
+        let queue = Arc::new(Mutex::new(
+            mails
+                .into_iter()
+                .enumerate()
+                .map(|(position, mail)| (position, false, mail))
+                .collect::<VecDeque<_>>(),
+        ));
         let results = Arc::new(Mutex::new(
-            (0..mail_count).map(|_| Ok(())).collect::<Vec<_>>(),
+            (0..mail_count)
+                .map(|_| Err(Error::NotAttempted))
+                .collect::<Vec<_>>(),
         ));
         let mut handlers = (0..thread_count)
             .map(|_| {
                 let mut mailer = self.clone();
                 let credentials = credentials.clone();
-                let mails = mails.clone();
+                let queue = queue.clone();
                 let results = results.clone();
-                thread::spawn(move || mailer.post_channel(credentials, mails, results))
+                thread::spawn(move || mailer.post_channel(credentials, queue, results))
             })
             .collect::<Vec<_>>();
 
@@ -346,11 +733,324 @@ where
         }
     }
 
-    pub fn post(&self, credentials: Credentials, mails: Vec<Mail>) -> Result<Vec<Result<()>>> {
+    fn post_transport(
+        &self,
+        credentials: Option<Credentials>,
+        mails: Vec<IndexedMail>,
+    ) -> Result<Vec<Result<()>>> {
         if self.config.parallel {
             self.post_parallel(credentials, mails)
         } else {
             self.post_serial(credentials, mails)
         }
     }
+
+    /// Delivers `mails` to the first MX host of `domain` that accepts a
+    /// connection, trying them in order of preference.
+    fn post_domain(&self, domain: &str, mails: Vec<IndexedMail>) -> Vec<Result<()>> {
+        let hosts = match resolver::mail_hosts(self.config.resolver.as_ref(), domain) {
+            Ok(hosts) => hosts,
+            Err(e) => return mails.iter().map(|_| Err(e.clone())).collect(),
+        };
+        let mut error = Error::ServerUnreachable;
+        for host in hosts {
+            if self.cancel.is_cancelled() {
+                error = Error::Cancelled;
+                break;
+            }
+            let mailer = self.with_server(Server::new(host, MX_PORT));
+            match mailer.post_transport(None, mails.clone()) {
+                Ok(results) => return results,
+                Err(e) => error = e,
+            }
+        }
+        mails.iter().map(|_| Err(error.clone())).collect()
+    }
+
+    fn post_direct(&self, mails: Vec<IndexedMail>) -> Result<Vec<Result<()>>> {
+        let mut results = (0..mails.len()).map(|_| Ok(())).collect::<Vec<_>>();
+        let mut domains: Vec<(String, Vec<usize>, Vec<IndexedMail>)> = vec![];
+        for (idx, mail) in mails.into_iter().enumerate() {
+            let domain = mail.1.to_domain();
+            match domains.iter_mut().find(|(d, _, _)| *d == domain) {
+                Some((_, indices, group)) => {
+                    indices.push(idx);
+                    group.push(mail);
+                }
+                None => domains.push((domain, vec![idx], vec![mail])),
+            }
+        }
+        for (domain, indices, group) in domains {
+            let group_results = self.post_domain(domain.as_str(), group);
+            for (idx, result) in indices.into_iter().zip(group_results) {
+                results[idx] = result;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Sends `mails` once, giving each the connection error when no
+    /// connection could be opened at all.
+    fn post_once(&self, credentials: &Credentials, mails: Vec<IndexedMail>) -> Vec<Result<()>> {
+        if self.cancel.is_cancelled() {
+            return mails.iter().map(|_| Err(Error::Cancelled)).collect();
+        }
+        let count = mails.len();
+        let results = if self.relays.is_some() || self.transport.is_some() {
+            self.post_transport(Some(credentials.clone()), mails)
+        } else {
+            self.post_direct(mails)
+        };
+        results.unwrap_or_else(|e| (0..count).map(|_| Err(e.clone())).collect())
+    }
+
+    fn defer(
+        &self,
+        queue: &mut DeferralQueue,
+        mail: &Mail,
+        index: usize,
+        previous: Option<&Deferred>,
+    ) {
+        if let Some(delay) = queue.defer(index, previous) {
+            if let Some(batch) = self.spool.as_ref() {
+                let _ = batch.update(index, SpoolState::Deferred, None);
+            }
+            self.logger.event(Event::MailDeferred {
+                subject: mail.subject.clone(),
+                to: mail.to.clone(),
+                delay,
+            });
+        }
+    }
+
+    /// Sends `mails` and returns the result for each by its id, in the
+    /// order given, with the Message-ID of each mail sent. Mails the
+    /// server asks to send later, as with a greylisting 450, are tried
+    /// again on the schedule of `Config::deferral`, so this only returns
+    /// once each has been sent, its deferral has expired or `cancel` is
+    /// cancelled.
+    pub fn post(
+        &self,
+        credentials: Credentials,
+        mails: Vec<Mail>,
+        cancel: &CancelToken,
+    ) -> Result<Vec<(String, Result<String>)>> {
+        let mailer = Mailer {
+            cancel: cancel.clone(),
+            ..self.clone()
+        };
+        Ok(mailer.post_deferring(credentials, mails))
+    }
+
+    fn post_deferring(
+        &self,
+        credentials: Credentials,
+        mut mails: Vec<Mail>,
+    ) -> Vec<(String, Result<String>)> {
+        for mail in mails.iter_mut() {
+            self.stamp(mail);
+        }
+        let mut results = self.post_once(&credentials, mails.iter().cloned().enumerate().collect());
+        let mut queue = DeferralQueue::new(self.config.deferral.clone());
+        for (index, result) in results.iter().enumerate() {
+            if matches!(result, Err(e) if e.deferrable()) {
+                self.defer(&mut queue, &mails[index], index, None);
+            }
+        }
+        loop {
+            let batch = queue.next_batch(&self.cancel);
+            if batch.is_empty() {
+                return mails
+                    .into_iter()
+                    .zip(results)
+                    .map(|(mail, result)| {
+                        let message_id = mail.message_id.unwrap_or_default();
+                        (mail.id, result.map(|()| message_id))
+                    })
+                    .collect();
+            }
+            let batch_mails = batch
+                .iter()
+                .map(|deferred| (deferred.index, mails[deferred.index].clone()))
+                .collect::<Vec<_>>();
+            let batch_results = self.post_once(&credentials, batch_mails);
+            for (deferred, result) in batch.iter().zip(batch_results) {
+                let index = deferred.index;
+                if matches!(&result, Err(e) if e.deferrable()) {
+                    self.defer(&mut queue, &mails[index], index, Some(deferred));
+                }
+                results[index] = result;
+            }
+        }
+    }
+
+    /// Sends the mails of `spool` that haven't gone out yet, updating
+    /// each entry as it goes, and returns the result for each by id. Run
+    /// again after a crash, it picks up exactly the mails left unsent.
+    pub fn post_spool(
+        &self,
+        credentials: Credentials,
+        spool: &Spool,
+        cancel: &CancelToken,
+    ) -> Result<Vec<(String, Result<String>)>> {
+        let (ids, mails): (Vec<_>, Vec<_>) = spool.pending()?.into_iter().unzip();
+        let batch = Arc::new(SpoolBatch::new(spool.clone(), ids.clone()));
+        let mailer = Mailer {
+            spool: Some(batch),
+            cancel: cancel.clone(),
+            ..self.clone()
+        };
+        let results = mailer.post_deferring(credentials, mails);
+        Ok(ids
+            .into_iter()
+            .zip(results)
+            .map(|(id, (_, result))| (id, result))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeServer, NullLogger};
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    fn mails() -> Vec<Mail> {
+        ["first", "second"]
+            .iter()
+            .map(|id| Mail {
+                id: id.to_string(),
+                subject: id.to_string(),
+                from: "sender@example.com".to_string(),
+                to: "rcpt@example.com".to_string(),
+                text: "hello".to_string(),
+                ..Mail::default()
+            })
+            .collect()
+    }
+
+    fn credentials() -> Credentials {
+        Credentials::new("user".to_string(), "secret".to_string())
+    }
+
+    #[test]
+    fn only_deferred_is_deferrable() {
+        assert!(Error::Deferred("450 greylisted".to_string()).deferrable());
+        assert!(!Error::ServerUnavailable.deferrable());
+        assert!(!Error::Network.deferrable());
+        assert!(!Error::Policy.deferrable());
+    }
+
+    #[test]
+    fn unreachable_relay_fails_each_mail() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = Server::new("127.0.0.1".to_string(), port);
+        let mailer = Mailer::new(server, testing::config(), NullLogger);
+        let results = mailer
+            .post(credentials(), mails(), &CancelToken::new())
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        for (_, result) in results {
+            assert!(matches!(result, Err(Error::ServerUnreachable)));
+        }
+    }
+
+    #[test]
+    fn failing_mail_leaves_channels_running() {
+        let server = FakeServer::start(|line| {
+            line.contains("<closing@example.com>")
+                .then(|| "421 shutting down".to_string())
+        });
+        for channels in [1, 2] {
+            let mut config = testing::config();
+            config.parallel(true).max_channels(channels);
+            let mailer = Mailer::new(server.server(), config, NullLogger);
+            let mut mails = mails();
+            mails[0].to = "closing@example.com".to_string();
+            mails.push(Mail {
+                id: "third".to_string(),
+                ..mails[1].clone()
+            });
+            let results = mailer
+                .post(credentials(), mails, &CancelToken::new())
+                .unwrap();
+            assert!(matches!(results[0].1, Err(Error::ServerUnavailable)));
+            assert!(results[1].1.is_ok() && results[2].1.is_ok());
+        }
+    }
+
+    #[test]
+    fn busy_relay_fails_over_to_next() {
+        let busy = FakeServer::start(|line| line.is_empty().then(|| "421 busy".to_string()));
+        let next = FakeServer::start(|_| None);
+        let mut second = Relay::new(next.server());
+        second.priority(1);
+        let relays = vec![Relay::new(busy.server()), second];
+        let mailer = Mailer::with_relays(relays, testing::config(), NullLogger);
+        let results = mailer
+            .post(credentials(), mails(), &CancelToken::new())
+            .unwrap();
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(busy.messages().len(), 0);
+        assert_eq!(next.messages().len(), 2);
+    }
+
+    #[test]
+    fn greylisted_mail_is_sent_again() {
+        let greylisted = AtomicBool::new(false);
+        let server = FakeServer::start(move |line| {
+            (line.contains("<rcpt@example.com>") && !greylisted.swap(true, Ordering::SeqCst))
+                .then(|| "450 greylisted".to_string())
+        });
+        let mut config = testing::config();
+        config.deferral(RetryPolicy::immediate());
+        let mailer = Mailer::new(server.server(), config, NullLogger);
+        let results = mailer
+            .post(credentials(), mails(), &CancelToken::new())
+            .unwrap();
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        let ids = results
+            .iter()
+            .map(|(id, _)| id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["first", "second"]);
+        assert_eq!(server.messages().len(), 2);
+    }
+
+    #[test]
+    fn spooled_mail_keeps_its_message_id() {
+        let directory = std::env::temp_dir().join(format!("spool-test-{}", Mail::new_id()));
+        let spool = Spool::open(directory.clone()).unwrap();
+        spool.enqueue(&mails().remove(0)).unwrap();
+        let greylisting = FakeServer::start(|line| {
+            line.contains("<rcpt@example.com>")
+                .then(|| "450 greylisted".to_string())
+        });
+        let mut config = testing::config();
+        config.message_id_domain("mail.example.org".to_string());
+        let mut deferral = RetryPolicy::immediate();
+        deferral.deadline(Duration::ZERO);
+        config.deferral(deferral);
+        let mailer = Mailer::new(greylisting.server(), config.clone(), NullLogger);
+        let cancel = CancelToken::new();
+        mailer.post_spool(credentials(), &spool, &cancel).unwrap();
+        let (_, deferred) = spool.pending().unwrap().remove(0);
+        let message_id = deferred.message_id.unwrap();
+        assert!(message_id.ends_with("@mail.example.org>"));
+        assert!(deferred.date.is_some());
+
+        let server = FakeServer::start(|_| None);
+        let mailer = Mailer::new(server.server(), config, NullLogger);
+        let results = mailer.post_spool(credentials(), &spool, &cancel).unwrap();
+        assert_eq!(results[0].1.as_ref().unwrap(), &message_id);
+        assert!(server.messages()[0].contains(message_id.as_str()));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -1,5 +1,7 @@
 use smtp::{Error, Event, Logger};
 use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use std::fs;
 
@@ -18,7 +20,7 @@ impl FileLogger {
             .write(true)
             .create(true)
             .open(path.clone())
-            .expect(format!("failed to open file: {}", path).as_str())
+            .unwrap_or_else(|_| panic!("failed to open file: {}", path))
     }
     pub(crate) fn file(path: String) -> FileLogger {
         FileLogger {
@@ -49,11 +51,7 @@ impl FileLogger {
 
 impl Clone for FileLogger {
     fn clone(&self) -> Self {
-        let file = if let Some(path) = self.path.clone() {
-            Some(FileLogger::open(path))
-        } else {
-            None
-        };
+        let file = self.path.clone().map(FileLogger::open);
         Self {
             enabled: self.enabled,
             path: self.path.clone(),
@@ -68,18 +66,38 @@ impl FileLogger {
     fn get_error_message(&self, error: Error) -> String {
         match error {
             Error::File(path) => format!("Failed to open file: {}", path),
+            Error::Transport(mes) => format!("Failed to hand over the mail: {}", mes),
+            Error::Dkim(reason) => format!("DKIM failed: {}", reason),
+            Error::Smime(reason) => format!("S/MIME failed: {}", reason),
+            Error::Timeout => "The mail took longer than its deadline to send.".to_string(),
+            Error::Cancelled => "Sending was cancelled before this mail.".to_string(),
+            Error::NotAttempted => "No connection was left to send the mail.".to_string(),
+            Error::Deferred(reply) => format!("The server asked to try again later: {}", reply),
+            Error::Header(name) => format!("The {} header contains a line break.", name),
+            Error::Unconfirmed => {
+                "The session was lost after the mail was sent, so it may have arrived.".to_string()
+            }
+            Error::HeaderName(name) => format!("\"{}\" is not a valid header name.", name),
+            Error::ReservedHeader(name) => {
+                format!("The {} header is written by the mailer itself.", name)
+            }
+            Error::DuplicateHeader(name) => format!("The {} header is given twice.", name),
             Error::Protocol => "There was an error on the mail server side.".to_string(),
             Error::MailBoxName(mailbox) => format!("Invalid email address <{}>", mailbox),
             Error::ServerUnreachable => "Can't reach the server, try again later.".to_string(),
             Error::ServerUnavailable => "Server abruptly ended the connection.".to_string(),
             Error::MIMENotSupported => {
-                "MIME not supported by server. Can't send attachments.".to_string()
+                "The message has 8-bit data but the server only accepts 7-bit.".to_string()
+            }
+            Error::LineTooLong => {
+                "The message has a line longer than SMTP allows (998 characters).".to_string()
             }
             Error::InvalidServer => {
                 "The server address you entered probably is not an SMTP one.".to_string()
             }
             Error::Network => "Disconnected due to a network issues.".to_string(),
             Error::DNS => "Failed to resolve hostname.".to_string(),
+            Error::Proxy => "The proxy refused to open a tunnel to the server.".to_string(),
             Error::InvalidCred => "The credentials you entered were invalidated by the server. \
     Make sure about the entered username and password."
                 .to_string(),
@@ -95,8 +113,8 @@ impl FileLogger {
         }
     }
 
-    fn event_connected(&self) {
-        println!("connected to server.");
+    fn event_connected(&self, address: SocketAddr) {
+        println!("connected to server at {}.", address);
     }
     fn event_disconnect(&self) {
         println!("connection closed.");
@@ -107,8 +125,8 @@ impl FileLogger {
             self.get_error_message(error.clone())
         );
     }
-    fn event_mail_sent(&self, subject: String, to: String) {
-        println!("--> sent [{}] to <{}>.", subject, to);
+    fn event_mail_sent(&self, subject: String, to: String, message_id: String) {
+        println!("--> sent [{}] to <{}> as {}.", subject, to, message_id);
     }
     fn event_mail_failed(&self, subject: String, to: String, error: Error) {
         eprintln!(
@@ -118,8 +136,21 @@ impl FileLogger {
             self.get_error_message(error.clone())
         );
     }
-    fn event_retrying(&self) {
-        eprintln!("--> retrying...");
+    fn event_mail_deferred(&self, subject: String, to: String, delay: Duration) {
+        eprintln!(
+            "--> will try sending [{}] to <{}> again in {} minute(s).",
+            subject,
+            to,
+            delay.as_secs().div_ceil(60)
+        );
+    }
+    fn event_retrying(&self, attempt: u32, delay: Duration, error: Error) {
+        eprintln!(
+            "--> {}\n--> retrying in {:.1}s (attempt {})...",
+            self.get_error_message(error),
+            delay.as_secs_f64(),
+            attempt
+        );
     }
 }
 
@@ -161,15 +192,26 @@ impl Logger for FileLogger {
     fn event(&self, event: Event) {
         if self.enabled {
             match event {
-                Event::Connected => self.event_connected(),
+                Event::Connected(address) => self.event_connected(address),
                 Event::FailedToConnect(e) => self.event_connection_failed(e),
                 Event::Disconnencted => self.event_disconnect(),
                 Event::FailToDisconnect(_) => (),
-                Event::Retry => self.event_retrying(),
-                Event::MailSent { subject, to } => self.event_mail_sent(subject, to),
+                Event::Retry {
+                    attempt,
+                    delay,
+                    error,
+                } => self.event_retrying(attempt, delay, error),
+                Event::MailSent {
+                    subject,
+                    to,
+                    message_id,
+                } => self.event_mail_sent(subject, to, message_id),
                 Event::FailedToSendMail { subject, to, error } => {
                     self.event_mail_failed(subject, to, error)
                 }
+                Event::MailDeferred { subject, to, delay } => {
+                    self.event_mail_deferred(subject, to, delay)
+                }
             }
         }
     }
@@ -2,26 +2,71 @@ mod input;
 mod logger;
 
 use input::MailFile;
-use smtp::{Config, Credentials, Mailer, Server};
-use std::{env::args, fs, process::exit};
+use smtp::{
+    CancelToken, Config, Credentials, Mailer, Proxy, Relay, RetryPolicy, Server, Spool, Timeouts,
+};
+use std::{env::args, fs, path::PathBuf, process::exit};
 
 use crate::logger::FileLogger;
 
 fn main() {
     println!("Smtp Client v0.1.0");
-    let args: Vec<String> = args().collect();
+    let mut args: Vec<String> = args().collect();
+    // `--resume` sends what is left in the spool instead of the file's mails
+    let resume = args.len() > 1 && args[1] == "--resume";
+    if resume {
+        args.remove(1);
+    }
     if args.len() < 2 {
         exit(1);
     }
     let mail_file = args[1].clone();
     let mail_file = fs::read_to_string(mail_file.clone())
-        .expect(format!("failed to open file: {}", mail_file).as_str());
+        .unwrap_or_else(|_| panic!("failed to open file: {}", mail_file));
 
     let mail_file: MailFile = toml::from_str(mail_file.as_str()).unwrap_or_else(|e| {
         eprintln!("mail file error: {}", e.message());
         exit(1)
     });
 
+    let spool = mail_file.config.as_ref().and_then(|c| c.spool.clone());
     let (server, mails, config, logfile, credentials) = mail_file.destruct();
-    let _ = Mailer::new(server, config, FileLogger::new(logfile)).post(credentials, mails);
+    let logger = FileLogger::new(logfile);
+    let mailer = match server {
+        Some(relays) => Mailer::with_relays(relays, config, logger),
+        None => Mailer::direct(config, logger),
+    };
+    // the first Ctrl-C finishes the mails under way, the second quits
+    let cancel = CancelToken::new();
+    let handler = cancel.clone();
+    let _ = ctrlc::set_handler(move || {
+        if handler.is_cancelled() {
+            exit(130);
+        }
+        eprintln!("cancelling, press Ctrl-C again to quit now...");
+        handler.cancel();
+    });
+    let spool = match spool {
+        Some(directory) => Spool::open(PathBuf::from(directory.as_str())).unwrap_or_else(|_| {
+            eprintln!("failed to open spool: {}", directory);
+            exit(1)
+        }),
+        None if resume => {
+            eprintln!("mail file error: --resume needs a spool in [config]");
+            exit(1)
+        }
+        None => {
+            let _ = mailer.post(credentials, mails, &cancel);
+            return;
+        }
+    };
+    if !resume {
+        for mail in mails.iter() {
+            if spool.enqueue(mail).is_err() {
+                eprintln!("failed to write to spool");
+                exit(1)
+            }
+        }
+    }
+    let _ = mailer.post_spool(credentials, &spool, &cancel);
 }
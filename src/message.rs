@@ -1,53 +1,524 @@
-use crate::{Error, Result};
-use mail_builder::MessageBuilder;
-use std::fs;
+use crate::header::{
+    check_header, check_header_name, encode_address, encode_message_id, encode_text,
+};
+use crate::html::html_to_text;
+use crate::payload::{Boundaries, Part, Payload};
+use crate::{DkimSigner, Envelope, Error, Result, Smime};
+use mail_builder::{
+    headers::{content_type::ContentType, date::Date, raw::Raw, HeaderType},
+    mime::MimePart,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fs,
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A mail to a single recipient. `id` names it in the results of
+/// `Mailer::post`; `Mail::default()` gives every mail a fresh one.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Mail {
+    pub id: String,
     pub subject: String,
     pub from: String,
     pub from_name: Option<String>,
     pub to: String,
     pub to_name: Option<String>,
     pub text: String,
-    pub attachments: Vec<String>,
+    /// An HTML body, sent with `text` as its plain-text alternative. When
+    /// `text` is empty it is made from the HTML.
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+    /// Further headers as written into the message. Set them with
+    /// `header` or the typed helpers, which check and encode them.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// The Message-ID, such as `<id@example.com>`. `Mailer` makes one
+    /// from `id` when it is unset.
+    #[serde(default)]
+    pub message_id: Option<String>,
+    /// Unix time of the Date header; unset, the time the mail is built.
+    #[serde(default)]
+    pub date: Option<i64>,
+    /// Numbers MIME boundaries instead of making them random.
+    #[serde(default)]
+    pub fixed_boundaries: bool,
+    /// Signs the message with DKIM once it is built.
+    #[serde(skip)]
+    pub dkim: Option<DkimSigner>,
+    /// Signs the body with S/MIME as the signer of `from`.
+    #[serde(default)]
+    pub sign: bool,
+    /// Encrypts the body with S/MIME to the certificate of `to`, after
+    /// signing it.
+    #[serde(default)]
+    pub encrypt: bool,
+    /// The signers and certificates `sign` and `encrypt` use.
+    #[serde(skip)]
+    pub smime: Option<Smime>,
+}
+
+/// A file sent along with a mail, read from a path at send time or held
+/// in memory. Inline attachments are images the HTML body shows through
+/// `cid:` URLs naming their `content_id`; they go with the HTML in a
+/// multipart/related part.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub content: Content,
+    /// The name the recipient sees, by default the file name of the path.
+    pub filename: Option<String>,
+    /// The MIME type, by default sniffed from the content or guessed from
+    /// the file name, and `application/octet-stream` failing both.
+    pub content_type: Option<String>,
+    pub inline: bool,
+    pub content_id: Option<String>,
+}
+
+/// Where the bytes of an attachment come from. A spool entry keeps it
+/// as a table naming the kind, with held bytes in base64.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Content {
+    Path(String),
+    Bytes(#[serde(with = "base64_bytes")] Vec<u8>),
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(general_purpose::STANDARD.encode(bytes).as_str())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        general_purpose::STANDARD
+            .decode(data)
+            .map_err(D::Error::custom)
+    }
 }
 
-fn path_file_name(path: &String) -> String {
-    std::path::PathBuf::from(path)
+impl Attachment {
+    pub fn new(path: String) -> Attachment {
+        Attachment {
+            content: Content::Path(path),
+            filename: None,
+            content_type: None,
+            inline: false,
+            content_id: None,
+        }
+    }
+    /// An attachment made in memory, such as a generated report.
+    pub fn bytes(filename: String, content: Vec<u8>) -> Attachment {
+        Attachment {
+            content: Content::Bytes(content),
+            filename: Some(filename),
+            content_type: None,
+            inline: false,
+            content_id: None,
+        }
+    }
+    /// An image shown by the HTML body as `cid:<content_id>`.
+    pub fn inline(path: String, content_id: String) -> Attachment {
+        Attachment {
+            content: Content::Path(path),
+            filename: None,
+            content_type: None,
+            inline: true,
+            content_id: Some(content_id),
+        }
+    }
+    pub fn filename(&mut self, value: String) -> &mut Attachment {
+        self.filename = Some(value);
+        self
+    }
+    pub fn content_type(&mut self, value: String) -> &mut Attachment {
+        self.content_type = Some(value);
+        self
+    }
+
+    /// The part of the attachment. A file is opened now, so a missing one
+    /// fails the mail before it is sent, but only read when it is written.
+    fn part(&self) -> Result<Part<'static>> {
+        let (content, file) = match &self.content {
+            Content::Path(path) => {
+                let error = |_| Error::File(path.clone());
+                let file = fs::File::open(path).map_err(error)?;
+                // enough of the file to tell its type
+                let mut head = vec![];
+                (&file).take(8192).read_to_end(&mut head).map_err(error)?;
+                (head, Some(file))
+            }
+            Content::Bytes(bytes) => (bytes.clone(), None),
+        };
+        let filename = match (&self.filename, &self.content) {
+            (Some(filename), _) => filename.clone(),
+            (None, Content::Path(path)) => path_file_name(path),
+            (None, Content::Bytes(_)) => "attachment".to_string(),
+        };
+        check_header("Content-Disposition", filename.as_str())?;
+        let content_type = match self.content_type.as_ref() {
+            Some(content_type) => {
+                check_header("Content-Type", content_type.as_str())?;
+                content_type.clone()
+            }
+            None => infer::get(&content)
+                .map(|kind| kind.mime_type())
+                .or_else(|| guess_content_type(filename.as_str()))
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+        };
+        let part = match file {
+            Some(_) => MimePart::new_binary(content_type, vec![]),
+            None => MimePart::new_binary(content_type, content),
+        };
+        let part = if self.inline {
+            part.header(
+                "Content-Disposition",
+                ContentType::new("inline").attribute("filename", filename),
+            )
+        } else {
+            part.attachment(filename)
+        };
+        let part = match self.content_id.as_ref() {
+            Some(content_id) => part.cid(content_id.clone()),
+            None => part,
+        };
+        Ok(match (file, &self.content) {
+            (Some(file), Content::Path(path)) => Part::File {
+                part,
+                path: path.clone(),
+                file,
+            },
+            _ => Part::Mime(part),
+        })
+    }
+}
+
+fn path_file_name(path: &str) -> String {
+    std::path::Path::new(path)
         .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
         .to_string()
 }
 
+/// Types of text formats, which have no magic numbers to sniff.
+fn guess_content_type(filename: &str) -> Option<&'static str> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    Some(match extension.to_ascii_lowercase().as_str() {
+        "txt" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "tsv" => "text/tab-separated-values",
+        "htm" | "html" => "text/html",
+        "md" => "text/markdown",
+        "ics" => "text/calendar",
+        "vcf" => "text/vcard",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        _ => return None,
+    })
+}
+
+impl Default for Mail {
+    fn default() -> Self {
+        Mail {
+            id: Mail::new_id(),
+            subject: String::new(),
+            from: String::new(),
+            from_name: None,
+            to: String::new(),
+            to_name: None,
+            text: String::new(),
+            html: None,
+            attachments: vec![],
+            headers: vec![],
+            message_id: None,
+            date: None,
+            fixed_boundaries: false,
+            dkim: None,
+            sign: false,
+            encrypt: false,
+            smime: None,
+        }
+    }
+}
+
 impl Mail {
-    pub fn final_text(&self) -> String {
-        self.text.replace(".\r\n", "..\r\n")
+    /// An id no other mail made by this process has.
+    pub fn new_id() -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        let count = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("{}.{}.{}", time, std::process::id(), count)
+    }
+    /// A Message-ID made of `id` and `domain`, or of `id` and the
+    /// sender's domain without one.
+    pub fn new_message_id(&self, domain: Option<&str>) -> String {
+        let domain = match domain {
+            Some(domain) => domain,
+            None => match self.from.rsplit_once('@') {
+                Some((_, domain)) if !domain.is_empty() => domain,
+                _ => "localhost",
+            },
+        };
+        // the id may be anything, but the left of a Message-ID is a dot-atom
+        let atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c);
+        let local = self
+            .id
+            .split('.')
+            .filter(|part| !part.is_empty())
+            .map(|part| part.replace(|c| !atext(c), "-"))
+            .collect::<Vec<_>>()
+            .join(".");
+        format!("<{}@{}>", local, domain)
+    }
+    pub fn check_headers(&self) -> Result<()> {
+        check_header("Subject", self.subject.as_str())?;
+        check_header("From", self.from.as_str())?;
+        check_header("To", self.to.as_str())?;
+        if let Some(name) = self.from_name.as_ref() {
+            check_header("From", name.as_str())?;
+        }
+        if let Some(name) = self.to_name.as_ref() {
+            check_header("To", name.as_str())?;
+        }
+        if let Some(message_id) = self.message_id.as_ref() {
+            check_header("Message-ID", message_id.as_str())?;
+        }
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            check_header_name(name.as_str())?;
+            check_header(name.as_str(), value.as_str())?;
+            if self.headers[..i]
+                .iter()
+                .any(|(other, _)| other.eq_ignore_ascii_case(name))
+            {
+                return Err(Error::DuplicateHeader(name.clone()));
+            }
+        }
+        Ok(())
+    }
+    /// Adds a header with an unstructured value, such as an `X-` header.
+    /// Headers the message is built with can't be given, nor can one
+    /// header be given twice.
+    pub fn header(&mut self, name: &str, value: &str) -> Result<&mut Mail> {
+        check_header(name, value)?;
+        self.raw_header(name, encode_text(value))
+    }
+    pub fn reply_to(&mut self, name: Option<&str>, address: &str) -> Result<&mut Mail> {
+        check_header("Reply-To", address)?;
+        if let Some(name) = name {
+            check_header("Reply-To", name)?;
+        }
+        self.raw_header("Reply-To", encode_address(name, address))
+    }
+    /// The message id of the mail this one answers.
+    pub fn in_reply_to(&mut self, message_id: &str) -> Result<&mut Mail> {
+        check_header("In-Reply-To", message_id)?;
+        self.raw_header("In-Reply-To", encode_message_id(message_id))
+    }
+    /// The message ids of the thread this mail belongs to, oldest first.
+    pub fn references(&mut self, message_ids: &[String]) -> Result<&mut Mail> {
+        let mut ids = vec![];
+        for id in message_ids {
+            check_header("References", id.as_str())?;
+            ids.push(encode_message_id(id.as_str()));
+        }
+        self.raw_header("References", ids.join(" "))
+    }
+    /// The RFC 2919 identifier of the mailing list the mail is sent to,
+    /// such as `news.example.com`.
+    pub fn list_id(&mut self, description: Option<&str>, id: &str) -> Result<&mut Mail> {
+        check_header("List-Id", id)?;
+        if let Some(description) = description {
+            check_header("List-Id", description)?;
+        }
+        self.raw_header("List-Id", encode_address(description, id))
+    }
+    /// Where the recipient can unsubscribe, as `https:` and `mailto:`
+    /// URIs (RFC 2369). With `one_click` mail clients may unsubscribe by
+    /// a single POST to the `https:` URI (RFC 8058), which is left out
+    /// when there is no such URI.
+    pub fn list_unsubscribe(&mut self, uris: &[String], one_click: bool) -> Result<&mut Mail> {
+        for uri in uris {
+            check_header("List-Unsubscribe", uri.as_str())?;
+        }
+        let value = uris
+            .iter()
+            .map(|uri| format!("<{}>", uri.trim()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.raw_header("List-Unsubscribe", value)?;
+        let https = uris
+            .iter()
+            .any(|uri| uri.trim().to_ascii_lowercase().starts_with("https:"));
+        if one_click && https {
+            self.raw_header(
+                "List-Unsubscribe-Post",
+                "List-Unsubscribe=One-Click".to_string(),
+            )?;
+        }
+        Ok(self)
+    }
+    fn raw_header(&mut self, name: &str, value: String) -> Result<&mut Mail> {
+        check_header_name(name)?;
+        check_header(name, value.as_str())?;
+        if self
+            .headers
+            .iter()
+            .any(|(other, _)| other.eq_ignore_ascii_case(name))
+        {
+            return Err(Error::DuplicateHeader(name.to_string()));
+        }
+        self.headers.push((name.to_string(), value));
+        Ok(self)
+    }
+    pub fn envelope(&self) -> Envelope {
+        Envelope::new(self.from.clone(), vec![self.to.clone()])
+    }
+    /// The lowercased domain part of the recipient address.
+    pub fn to_domain(&self) -> String {
+        self.to
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or("")
+            .to_lowercase()
     }
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        let mut builder = MessageBuilder::new()
-            .from((
-                self.from_name.clone().unwrap_or("".to_owned()),
-                self.from.clone(),
-            ))
-            .to((
-                self.to_name.clone().unwrap_or("".to_owned()),
-                self.to.clone(),
-            ))
-            .subject(self.subject.as_str())
-            .text_body(self.final_text());
-        for att in self.attachments.iter() {
-            let content = fs::read(att).map_err(|_| Error::File(att.clone()))?;
-            builder = builder.binary_attachment(
-                infer::get_from_path(att)
-                    .map_err(|_| Error::File(att.clone()))?
-                    .unwrap()
-                    .to_string(),
-                path_file_name(att),
-                content,
-            );
-        }
-        Ok(builder.write_to_vec().unwrap())
+        self.payload()?.to_bytes()
+    }
+    /// The message, with attachment files opened but not yet read.
+    pub(crate) fn payload(&self) -> Result<Payload> {
+        self.check_headers()?;
+        let raw = |value: String| HeaderType::from(Raw::new(value));
+        let mut headers: Vec<(Cow<str>, HeaderType)> = vec![
+            (
+                "From".into(),
+                raw(encode_address(
+                    self.from_name.as_deref(),
+                    self.from.as_str(),
+                )),
+            ),
+            (
+                "To".into(),
+                raw(encode_address(self.to_name.as_deref(), self.to.as_str())),
+            ),
+            ("Subject".into(), raw(encode_text(self.subject.as_str()))),
+        ];
+        for (name, value) in self.headers.iter() {
+            headers.push((name.as_str().into(), raw(value.clone())));
+        }
+        let message_id = match self.message_id.as_ref() {
+            Some(message_id) => encode_message_id(message_id.as_str()),
+            None => self.new_message_id(None),
+        };
+        let date = self.date.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0)
+        });
+        headers.push(("Message-ID".into(), raw(message_id)));
+        headers.push(("Date".into(), Date::new(date).into()));
+        let mut boundaries = Boundaries::new(self.fixed_boundaries);
+        let text = match self.html.as_ref() {
+            Some(html) if self.text.is_empty() => html_to_text(html.as_str()),
+            _ => self.text.clone(),
+        };
+        let mut body = Part::Mime(MimePart::new_text(text));
+        let (inline, mut attached): (Vec<_>, Vec<_>) =
+            self.attachments.iter().partition(|att| att.inline);
+        match self.html.as_ref() {
+            Some(html) => {
+                let mut html = Part::Mime(MimePart::new_html(html.as_str()));
+                if !inline.is_empty() {
+                    let mut parts = vec![html];
+                    for att in inline {
+                        parts.push(att.part()?);
+                    }
+                    html = boundaries.multipart(
+                        ContentType::new("multipart/related").attribute("type", "text/html"),
+                        parts,
+                    );
+                }
+                body = boundaries
+                    .multipart(ContentType::new("multipart/alternative"), vec![body, html]);
+            }
+            // without HTML nothing refers to inline images
+            None => attached.extend(inline),
+        }
+        if !attached.is_empty() {
+            let mut parts = vec![body];
+            for att in attached {
+                parts.push(att.part()?);
+            }
+            body = boundaries.multipart(ContentType::new("multipart/mixed"), parts);
+        }
+        if self.sign || self.encrypt {
+            let smime = self
+                .smime
+                .as_ref()
+                .ok_or_else(|| Error::Smime("no S/MIME certificates".to_string()))?;
+            if self.sign {
+                body = smime.sign(self.from.as_str(), body, date, &mut boundaries)?;
+            }
+            if self.encrypt {
+                body = smime.encrypt(self.to.as_str(), body)?;
+            }
+        }
+        let mut payload = Payload::new(headers, body);
+        if let Some(signer) = self.dkim.as_ref() {
+            payload.prepend(signer.sign(&payload, date)?.into_bytes());
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_value_with_line_break_is_refused() {
+        let mut mail = Mail::default();
+        assert!(matches!(
+            mail.header("X-Note", "hi\r\nBcc: victim@example.com"),
+            Err(Error::Header(_))
+        ));
+        assert!(matches!(
+            mail.header("X-Note", "hi\n"),
+            Err(Error::Header(_))
+        ));
+        assert!(mail.headers.is_empty());
+        assert!(mail.header("X-Note", "hi").is_ok());
+    }
+
+    #[test]
+    fn long_subject_is_folded() {
+        let mail = Mail {
+            from: "sender@example.com".to_string(),
+            to: "rcpt@example.com".to_string(),
+            subject: "Grüße aus Köln — ".repeat(8),
+            ..Mail::default()
+        };
+        let message = String::from_utf8(mail.to_bytes().unwrap()).unwrap();
+        let (headers, _) = message.split_once("\r\n\r\n").unwrap();
+        let lines = headers.split("\r\n").collect::<Vec<_>>();
+        assert!(lines.iter().all(|line| line.len() <= 78));
+        let subject = lines
+            .iter()
+            .position(|line| line.starts_with("Subject:"))
+            .unwrap();
+        assert!(lines[subject + 1].starts_with(' '));
     }
 }
@@ -0,0 +1,216 @@
+use crate::header::fold;
+use crate::{Error, Result};
+use base64::{engine::general_purpose, Engine};
+use mail_builder::{
+    headers::{content_type::ContentType, Header, HeaderType},
+    mime::{make_boundary, MimePart},
+};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{Read, Seek, SeekFrom},
+};
+
+/// Bytes of a file that fill one 76-character line of base64.
+const LINE_BYTES: usize = 57;
+/// Lines of base64 made from each read of an attachment file.
+const CHUNK_LINES: usize = 1024;
+
+/// A part of a message being built. Attachment files stay on disk as
+/// `File` parts, of which only the headers are kept in `part`. A
+/// `Payload` part was written already, such as a part that is signed.
+pub(crate) enum Part<'x> {
+    Mime(MimePart<'x>),
+    File {
+        part: MimePart<'x>,
+        path: String,
+        file: fs::File,
+    },
+    Multipart {
+        content_type: ContentType<'x>,
+        boundary: String,
+        parts: Vec<Part<'x>>,
+    },
+    Payload(Payload),
+}
+
+/// Boundaries of the multiparts of one message: random, or numbered in
+/// the order the parts are made.
+pub(crate) struct Boundaries {
+    fixed: bool,
+    count: usize,
+}
+
+impl Boundaries {
+    pub(crate) fn new(fixed: bool) -> Boundaries {
+        Boundaries { fixed, count: 0 }
+    }
+
+    pub(crate) fn multipart<'x>(
+        &mut self,
+        content_type: ContentType<'x>,
+        parts: Vec<Part<'x>>,
+    ) -> Part<'x> {
+        let boundary = if self.fixed {
+            self.count += 1;
+            format!("=_part_{}", self.count)
+        } else {
+            make_boundary("_")
+        };
+        Part::Multipart {
+            content_type,
+            boundary,
+            parts,
+        }
+    }
+}
+
+enum Segment {
+    Bytes(Vec<u8>),
+    File { path: String, file: fs::File },
+}
+
+/// A message ready to be written: headers and the parts held in memory
+/// as bytes, and attachment files, which are read and base64-encoded a
+/// chunk at a time as the message is written. Writing it takes the same
+/// memory however large the files are.
+pub(crate) struct Payload {
+    segments: Vec<Segment>,
+}
+
+impl Payload {
+    pub(crate) fn new(headers: Vec<(Cow<str>, HeaderType)>, body: Part) -> Payload {
+        let mut payload = Payload { segments: vec![] };
+        let mut out = vec![];
+        write_headers(&headers, &mut out);
+        payload.push_part(body, &mut out);
+        payload.segments.push(Segment::Bytes(out));
+        payload
+    }
+
+    /// Writes the parts as mail-builder would, except that the body of
+    /// a file is left to a segment of its own.
+    fn push_part(&mut self, part: Part, out: &mut Vec<u8>) {
+        match part {
+            Part::Mime(part) => {
+                // writing to a Vec can't fail
+                let _ = part.write_part(&mut *out);
+            }
+            Part::File { part, path, file } => {
+                write_headers(&part.headers, out);
+                out.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n\r\n");
+                self.segments.push(Segment::Bytes(std::mem::take(out)));
+                self.segments.push(Segment::File { path, file });
+            }
+            Part::Multipart {
+                content_type,
+                boundary,
+                parts,
+            } => {
+                let content_type = content_type.attribute("boundary", boundary.clone());
+                out.extend_from_slice(b"Content-Type: ");
+                let _ = content_type.write_header(&mut *out, 14);
+                out.extend_from_slice(b"\r\n");
+                for part in parts {
+                    out.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+                    self.push_part(part, out);
+                }
+                out.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+            }
+            Part::Payload(payload) => {
+                for segment in payload.segments {
+                    match segment {
+                        Segment::Bytes(bytes) => out.extend(bytes),
+                        file => {
+                            self.segments.push(Segment::Bytes(std::mem::take(out)));
+                            self.segments.push(file);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Puts `bytes`, such as a header, before the rest of the message.
+    pub(crate) fn prepend(&mut self, bytes: Vec<u8>) {
+        self.segments.insert(0, Segment::Bytes(bytes));
+    }
+
+    /// Passes the bytes held in memory to `check`, in order. Files only
+    /// add whole lines of base64, so they are left out.
+    pub(crate) fn check(&self, mut check: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        for segment in self.segments.iter() {
+            if let Segment::Bytes(bytes) = segment {
+                check(bytes.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the whole message to `out` a chunk at a time. It can be
+    /// written any number of times.
+    pub(crate) fn write(&self, mut out: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Bytes(bytes) => out(bytes.as_slice())?,
+                Segment::File { path, file } => write_file(path, file, &mut out)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut message = vec![];
+        self.write(|chunk| {
+            message.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(message)
+    }
+}
+
+fn write_headers(headers: &[(Cow<str>, HeaderType)], out: &mut Vec<u8>) {
+    for (name, value) in headers {
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(b": ");
+        match value {
+            // folded here, as mail-builder can leave two encoded-words on a line
+            HeaderType::Raw(raw) => {
+                out.extend_from_slice(fold(&raw.raw, name.len() + 2).as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            value => {
+                let _ = value.write_header(&mut *out, name.len() + 2);
+            }
+        }
+    }
+}
+
+fn write_file(
+    path: &str,
+    mut file: &fs::File,
+    out: &mut impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let error = |_| Error::File(path.to_string());
+    file.seek(SeekFrom::Start(0)).map_err(error)?;
+    let mut chunk = vec![0; LINE_BYTES * CHUNK_LINES];
+    let mut encoded = String::with_capacity(CHUNK_LINES * 78);
+    loop {
+        let mut read = 0;
+        while read < chunk.len() {
+            match file.read(&mut chunk[read..]).map_err(error)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        encoded.clear();
+        for line in chunk[..read].chunks(LINE_BYTES) {
+            general_purpose::STANDARD.encode_string(line, &mut encoded);
+            encoded.push_str("\r\n");
+        }
+        out(encoded.as_bytes())?;
+        if read < chunk.len() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use base64::{engine::general_purpose, Engine};
+
+pub(crate) const TAG_INTEGER: u8 = 0x02;
+pub(crate) const TAG_BIT_STRING: u8 = 0x03;
+pub(crate) const TAG_OCTET_STRING: u8 = 0x04;
+pub(crate) const TAG_OID: u8 = 0x06;
+pub(crate) const TAG_SEQUENCE: u8 = 0x30;
+pub(crate) const TAG_SET: u8 = 0x31;
+
+pub(crate) const OID_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// The label and DER of each PEM block in `pem`, in order.
+pub(crate) fn decode(pem: &str) -> Vec<(String, Vec<u8>)> {
+    let mut blocks = vec![];
+    let mut rest = pem;
+    while let Some(start) = rest.find("-----BEGIN ") {
+        rest = &rest[start + 11..];
+        let label = match rest.find("-----") {
+            Some(end) => rest[..end].to_string(),
+            None => break,
+        };
+        rest = &rest[label.len() + 5..];
+        let end_marker = format!("-----END {}-----", label);
+        let end = match rest.find(end_marker.as_str()) {
+            Some(end) => end,
+            None => break,
+        };
+        let text = rest[..end]
+            .lines()
+            // headers of encrypted keys, which aren't supported
+            .filter(|line| !line.contains(':'))
+            .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+            .collect::<String>();
+        if let Ok(der) = general_purpose::STANDARD.decode(text) {
+            blocks.push((label, der));
+        }
+        rest = &rest[end + end_marker.len()..];
+    }
+    blocks
+}
+
+/// Reads one DER element off `input`: its tag, its contents and what
+/// follows it.
+pub(crate) fn read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// Reads one DER element off `input`, tag and length included, and
+/// what follows it.
+pub(crate) fn element(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, _, rest) = read(input)?;
+    Some((&input[..input.len() - rest.len()], rest))
+}
+
+/// Reads an element that must have `tag`.
+pub(crate) fn expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match read(input)? {
+        (found, contents, rest) if found == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// The algorithm OID and key bits of a SubjectPublicKeyInfo.
+pub(crate) fn public_key_info(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (info, _) = expect(der, TAG_SEQUENCE)?;
+    let (algorithm, rest) = expect(info, TAG_SEQUENCE)?;
+    let (oid, _) = expect(algorithm, TAG_OID)?;
+    let (bits, _) = expect(rest, TAG_BIT_STRING)?;
+    // the first byte counts unused bits, which keys don't have
+    match bits.split_first()? {
+        (0, key) => Some((oid, key)),
+        _ => None,
+    }
+}
+
+/// A DER element of `tag` holding `contents`.
+pub(crate) fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend(&bytes[skip..]);
+    }
+    out.extend(contents);
+    out
+}
@@ -0,0 +1,347 @@
+use crate::{
+    connection::MailerConnection, Credentials, Error, Event, Logger, Mail, Mailer, Result,
+};
+use std::{
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub max_messages: u32,
+    pub keepalive: u64,
+    pub idle_timeout: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PoolConfig {
+    pub fn new() -> PoolConfig {
+        PoolConfig {
+            max_size: 4,
+            max_messages: 100,
+            keepalive: 30,
+            idle_timeout: 300,
+        }
+    }
+    pub fn max_size(&mut self, value: u32) -> &mut PoolConfig {
+        self.max_size = value;
+        self
+    }
+    pub fn max_messages(&mut self, value: u32) -> &mut PoolConfig {
+        self.max_messages = value;
+        self
+    }
+    pub fn keepalive(&mut self, value: u64) -> &mut PoolConfig {
+        self.keepalive = value;
+        self
+    }
+    pub fn idle_timeout(&mut self, value: u64) -> &mut PoolConfig {
+        self.idle_timeout = value;
+        self
+    }
+}
+
+struct Idle<L: Logger> {
+    con: MailerConnection<L>,
+    messages: u32,
+    /// When it was given back, which `idle_timeout` counts from.
+    used: Instant,
+    /// When it was given back or last answered a NOOP.
+    pinged: Instant,
+}
+
+struct PoolState<L: Logger> {
+    idle: Vec<Idle<L>>,
+    open: u32,
+}
+
+struct PoolInner<L: Logger + 'static> {
+    mailer: Mailer<L>,
+    credentials: Credentials,
+    config: PoolConfig,
+    state: Mutex<PoolState<L>>,
+    available: Condvar,
+}
+
+/// Keeps up to `max_size` authenticated connections open and lends them
+/// to callers on any thread. Idle connections get a NOOP every
+/// `keepalive` seconds, are closed after `idle_timeout` seconds unused,
+/// and are replaced once they have carried `max_messages` mails.
+pub struct MailerPool<L: Logger + 'static> {
+    inner: Arc<PoolInner<L>>,
+}
+
+/// A connection lent by a `MailerPool`, given back when dropped.
+pub struct PooledConnection<'a, L: Logger + 'static> {
+    pool: &'a PoolInner<L>,
+    con: Option<MailerConnection<L>>,
+    messages: u32,
+    broken: bool,
+}
+
+impl<L: Logger> PoolInner<L> {
+    fn close(&self, mut con: MailerConnection<L>) {
+        match con.close() {
+            Ok(_) => self.mailer.logger.event(Event::Disconnencted),
+            Err(e) => self.mailer.logger.event(Event::FailToDisconnect(e)),
+        }
+    }
+
+    /// Frees the slot of a connection that won't be used again.
+    fn retire(&self, con: Option<MailerConnection<L>>) {
+        self.state.lock().unwrap().open -= 1;
+        self.available.notify_one();
+        if let Some(con) = con {
+            self.close(con);
+        }
+    }
+
+    fn release(&self, con: MailerConnection<L>, messages: u32) {
+        if messages >= self.config.max_messages {
+            return self.retire(Some(con));
+        }
+        let now = Instant::now();
+        self.state.lock().unwrap().idle.push(Idle {
+            con,
+            messages,
+            used: now,
+            pinged: now,
+        });
+        self.available.notify_one();
+    }
+
+    fn open(&self) -> Result<MailerConnection<L>> {
+        match self.mailer.connect(self.credentials.clone()) {
+            Ok(con) => {
+                self.mailer.logger.event(Event::Connected(con.address()));
+                Ok(con)
+            }
+            Err(e) => {
+                self.mailer.logger.event(Event::FailedToConnect(e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    /// Pings connections idle for longer than `keepalive` and closes the
+    /// ones that fail or have been idle past `idle_timeout`.
+    fn keepalive(&self) {
+        let keepalive = Duration::new(self.config.keepalive, 0);
+        let idle_timeout = Duration::new(self.config.idle_timeout, 0);
+        let stale = {
+            let mut state = self.state.lock().unwrap();
+            let (stale, fresh) = state.idle.drain(..).partition::<Vec<_>, _>(|idle| {
+                idle.pinged.elapsed() >= keepalive || idle.used.elapsed() >= idle_timeout
+            });
+            state.idle = fresh;
+            stale
+        };
+        for mut idle in stale {
+            let alive = idle.used.elapsed() < idle_timeout && idle.con.noop().is_ok();
+            if alive {
+                idle.pinged = Instant::now();
+                self.state.lock().unwrap().idle.push(idle);
+                self.available.notify_one();
+            } else {
+                self.retire(Some(idle.con));
+            }
+        }
+    }
+}
+
+impl<L: Logger> MailerPool<L> {
+    pub fn new(mailer: Mailer<L>, credentials: Credentials, config: PoolConfig) -> MailerPool<L> {
+        let keepalive = Duration::new(config.keepalive.max(1), 0);
+        let inner = Arc::new(PoolInner {
+            mailer,
+            credentials,
+            config,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            available: Condvar::new(),
+        });
+        let weak: Weak<PoolInner<L>> = Arc::downgrade(&inner);
+        thread::spawn(move || loop {
+            thread::sleep(keepalive);
+            match weak.upgrade() {
+                Some(inner) => inner.keepalive(),
+                None => break,
+            }
+        });
+        MailerPool { inner }
+    }
+
+    /// Takes an idle connection, opens a new one if the pool isn't full,
+    /// or else waits for another caller to give one back.
+    pub fn get(&self) -> Result<PooledConnection<'_, L>> {
+        let pool = self.inner.as_ref();
+        let mut state = pool.state.lock().unwrap();
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                return Ok(PooledConnection {
+                    pool,
+                    con: Some(idle.con),
+                    messages: idle.messages,
+                    broken: false,
+                });
+            }
+            if state.open < pool.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match pool.open() {
+                    Ok(con) => Ok(PooledConnection {
+                        pool,
+                        con: Some(con),
+                        messages: 0,
+                        broken: false,
+                    }),
+                    Err(e) => {
+                        pool.retire(None);
+                        Err(e)
+                    }
+                };
+            }
+            state = pool.available.wait(state).unwrap();
+        }
+    }
+
+    /// Sends `mail` on any connection and returns its Message-ID.
+    pub fn send(&self, mail: &Mail) -> Result<String> {
+        self.get()?.send(mail)
+    }
+}
+
+impl<L: Logger> Drop for MailerPool<L> {
+    fn drop(&mut self) {
+        let idle = std::mem::take(&mut self.inner.state.lock().unwrap().idle);
+        for idle in idle {
+            self.inner.close(idle.con);
+        }
+    }
+}
+
+impl<L: Logger> PooledConnection<'_, L> {
+    /// Sends `mail` and returns its Message-ID. A connection that fails
+    /// on the network or the protocol is closed instead of going back to
+    /// the pool, while one whose mail was only refused is kept.
+    pub fn send(&mut self, mail: &Mail) -> Result<String> {
+        let con = self.con.as_mut().unwrap();
+        self.messages += 1;
+        let result = self.pool.mailer.deliver(con, mail);
+        if let Err(e) = &result {
+            if e.retriable() || matches!(e, Error::Protocol) || !con.is_open() {
+                self.broken = true;
+            }
+        }
+        result
+    }
+}
+
+impl<L: Logger> Drop for PooledConnection<'_, L> {
+    fn drop(&mut self) {
+        if let Some(con) = self.con.take() {
+            if self.broken {
+                self.pool.retire(Some(con));
+            } else {
+                self.pool.release(con, self.messages);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeServer, NullLogger};
+
+    fn pool(server: &FakeServer) -> MailerPool<NullLogger> {
+        let mailer = Mailer::new(server.server(), testing::config(), NullLogger);
+        let credentials = Credentials::new("user".to_string(), "secret".to_string());
+        let mut config = PoolConfig::new();
+        config.max_size(1);
+        MailerPool::new(mailer, credentials, config)
+    }
+
+    fn mail(to: &str) -> Mail {
+        Mail {
+            from: "sender@example.com".to_string(),
+            to: to.to_string(),
+            subject: "pooled".to_string(),
+            text: "hello".to_string(),
+            ..Mail::default()
+        }
+    }
+
+    #[test]
+    fn refused_recipient_keeps_connection() {
+        let server = FakeServer::start(|line| {
+            line.contains("<refused@example.com>")
+                .then(|| "550 no such user".to_string())
+        });
+        let pool = pool(&server);
+        assert!(matches!(
+            pool.send(&mail("refused@example.com")),
+            Err(Error::Policy)
+        ));
+        assert!(pool.send(&mail("rcpt@example.com")).is_ok());
+        assert_eq!(server.sessions(), 1);
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    #[test]
+    fn keepalive_closes_connections_idle_too_long() {
+        let server = FakeServer::start(|_| None);
+        let mailer = Mailer::new(server.server(), testing::config(), NullLogger);
+        let credentials = Credentials::new("user".to_string(), "secret".to_string());
+        let mut config = PoolConfig::new();
+        config.keepalive(0).idle_timeout(1);
+        let pool = MailerPool::new(mailer, credentials, config);
+        assert!(pool.send(&mail("rcpt@example.com")).is_ok());
+        // pinged, but not used since
+        pool.inner.keepalive();
+        assert_eq!(pool.inner.state.lock().unwrap().idle.len(), 1);
+        thread::sleep(Duration::from_millis(1100));
+        pool.inner.keepalive();
+        let state = pool.inner.state.lock().unwrap();
+        assert_eq!((state.idle.len(), state.open), (0, 0));
+    }
+
+    #[test]
+    fn unavailable_server_drops_connection() {
+        let server = FakeServer::start(|line| {
+            line.contains("<closing@example.com>")
+                .then(|| "421 shutting down".to_string())
+        });
+        let pool = pool(&server);
+        assert!(matches!(
+            pool.send(&mail("closing@example.com")),
+            Err(Error::ServerUnavailable)
+        ));
+        assert!(pool.send(&mail("rcpt@example.com")).is_ok());
+        assert_eq!(server.sessions(), 2);
+    }
+
+    #[test]
+    fn protocol_error_drops_connection() {
+        let server = FakeServer::start(|line| {
+            line.contains("<garbled@example.com>")
+                .then(|| "299 what".to_string())
+        });
+        let pool = pool(&server);
+        assert!(matches!(
+            pool.send(&mail("garbled@example.com")),
+            Err(Error::Protocol)
+        ));
+        assert!(pool.send(&mail("rcpt@example.com")).is_ok());
+        assert_eq!(server.sessions(), 2);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{retry::random_unit, Server};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A server a `Mailer` may send through. Servers with a lower `priority`
+/// are tried first; among servers of equal priority connections are
+/// spread in proportion to `weight`.
+#[derive(Clone)]
+pub struct Relay {
+    pub server: Server,
+    pub priority: u32,
+    pub weight: u32,
+}
+
+impl Relay {
+    pub fn new(server: Server) -> Relay {
+        Relay {
+            server,
+            priority: 0,
+            weight: 1,
+        }
+    }
+    pub fn priority(&mut self, value: u32) -> &mut Relay {
+        self.priority = value;
+        self
+    }
+    pub fn weight(&mut self, value: u32) -> &mut Relay {
+        self.weight = value;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Health {
+    failures: u32,
+    tripped_until: Option<Instant>,
+}
+
+/// The relays of a mailer with the health of each, shared by all its
+/// channels. A relay that fails `threshold` times in a row is tripped
+/// for `cooldown`: it goes to the back of the order until then, so it
+/// is only tried once every healthy relay has failed too.
+pub(crate) struct Relays {
+    relays: Vec<Relay>,
+    health: Mutex<Vec<Health>>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Relays {
+    pub(crate) fn new(relays: Vec<Relay>, threshold: u32, cooldown: Duration) -> Relays {
+        let health = Mutex::new(vec![Health::default(); relays.len()]);
+        Relays {
+            relays,
+            health,
+            threshold,
+            cooldown,
+        }
+    }
+
+    pub(crate) fn server(&self, relay: usize) -> &Server {
+        &self.relays[relay].server
+    }
+
+    /// The relays to try for a new connection, best first: healthy
+    /// before tripped, then by priority, then a weighted draw.
+    pub(crate) fn order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+        let tripped = |relay: usize| health[relay].tripped_until.is_some_and(|t| t > now);
+        let mut left = (0..self.relays.len()).collect::<Vec<_>>();
+        let mut order = vec![];
+        while !left.is_empty() {
+            let best = left
+                .iter()
+                .map(|&relay| (tripped(relay), self.relays[relay].priority))
+                .min()
+                .unwrap();
+            let group = left
+                .iter()
+                .copied()
+                .filter(|&relay| (tripped(relay), self.relays[relay].priority) == best)
+                .collect::<Vec<_>>();
+            let total = group
+                .iter()
+                .map(|&relay| self.relays[relay].weight.max(1) as f64)
+                .sum::<f64>();
+            let mut draw = random_unit() * total;
+            let mut pick = group[group.len() - 1];
+            for &relay in group.iter() {
+                draw -= self.relays[relay].weight.max(1) as f64;
+                if draw < 0.0 {
+                    pick = relay;
+                    break;
+                }
+            }
+            left.retain(|&relay| relay != pick);
+            order.push(pick);
+        }
+        order
+    }
+
+    pub(crate) fn succeeded(&self, relay: usize) {
+        self.health.lock().unwrap()[relay] = Health::default();
+    }
+
+    pub(crate) fn failed(&self, relay: usize) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[relay];
+        health.failures += 1;
+        if health.failures >= self.threshold {
+            health.failures = 0;
+            health.tripped_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
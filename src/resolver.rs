@@ -0,0 +1,595 @@
+use crate::{Error, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const MX_PORT: u16 = 25;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exchange {
+    pub preference: u16,
+    pub host: String,
+}
+
+impl Exchange {
+    pub fn new(preference: u16, host: String) -> Exchange {
+        Exchange { preference, host }
+    }
+}
+
+/// Name resolution used to reach servers. `mx` returns an empty list
+/// when the domain has no MX records, in which case callers fall back
+/// to the domain's own addresses.
+pub trait Resolver: Send + Sync {
+    fn mx(&self, domain: &str) -> Result<Vec<Exchange>>;
+    fn addresses(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>>;
+    /// Host names the PTR records of `ip` point to.
+    fn reverse(&self, _ip: IpAddr) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+}
+
+/// Returns the hosts to try for `domain`, ordered by MX preference,
+/// or the domain itself when it publishes no MX records.
+pub(crate) fn mail_hosts(resolver: &dyn Resolver, domain: &str) -> Result<Vec<String>> {
+    let mut exchanges = resolver.mx(domain)?;
+    if exchanges.is_empty() {
+        return Ok(vec![domain.to_string()]);
+    }
+    exchanges.sort_by_key(|exchange| exchange.preference);
+    Ok(exchanges.drain(..).map(|exchange| exchange.host).collect())
+}
+
+/// Resolves addresses through the operating system and MX records by
+/// querying the first nameserver in `/etc/resolv.conf` over UDP, or over
+/// TCP when the reply doesn't fit in a datagram.
+#[derive(Clone)]
+pub struct SystemResolver {
+    nameserver: Option<SocketAddr>,
+    timeout: u64,
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemResolver {
+    pub fn new() -> SystemResolver {
+        SystemResolver {
+            nameserver: None,
+            timeout: 5,
+        }
+    }
+    pub fn nameserver(&mut self, value: SocketAddr) -> &mut SystemResolver {
+        self.nameserver = Some(value);
+        self
+    }
+    pub fn timeout(&mut self, value: u64) -> &mut SystemResolver {
+        self.timeout = value;
+        self
+    }
+
+    fn system_nameserver() -> SocketAddr {
+        fs::read_to_string("/etc/resolv.conf")
+            .ok()
+            .and_then(|conf| {
+                conf.lines().find_map(|line| {
+                    let mut words = line.split_whitespace();
+                    if words.next() == Some("nameserver") {
+                        words.next()?.parse::<IpAddr>().ok()
+                    } else {
+                        None
+                    }
+                })
+            })
+            .map(|ip| SocketAddr::new(ip, 53))
+            .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53))
+    }
+
+    fn query(&self, name: &str, qtype: u16) -> Result<Vec<u8>> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or(0);
+        let packet = dns::query(id, name, qtype)?;
+        let nameserver = self
+            .nameserver
+            .unwrap_or_else(SystemResolver::system_nameserver);
+        let reply = self.query_udp(nameserver, &packet)?;
+        if dns::truncated(&reply)? {
+            self.query_tcp(nameserver, &packet)
+        } else {
+            Ok(reply)
+        }
+    }
+
+    fn query_udp(&self, nameserver: SocketAddr, packet: &[u8]) -> Result<Vec<u8>> {
+        let local: SocketAddr = if nameserver.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).map_err(|_| Error::DNS)?;
+        socket
+            .set_read_timeout(Some(Duration::new(self.timeout, 0)))
+            .map_err(|_| Error::DNS)?;
+        socket.connect(nameserver).map_err(|_| Error::DNS)?;
+        socket.send(packet).map_err(|_| Error::DNS)?;
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = socket.recv(&mut buf).map_err(|_| Error::DNS)?;
+            // ignore stray datagrams that don't answer our query
+            if len >= 2 && buf[..2] == packet[..2] {
+                return Ok(buf[..len].to_vec());
+            }
+        }
+    }
+
+    /// Sends `packet` over TCP, each message preceded by its length
+    /// (RFC 1035 section 4.2.2).
+    fn query_tcp(&self, nameserver: SocketAddr, packet: &[u8]) -> Result<Vec<u8>> {
+        let timeout = Duration::new(self.timeout, 0);
+        let mut stream =
+            TcpStream::connect_timeout(&nameserver, timeout).map_err(|_| Error::DNS)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .and_then(|_| stream.set_write_timeout(Some(timeout)))
+            .map_err(|_| Error::DNS)?;
+        let mut message = (packet.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(packet);
+        stream.write_all(&message).map_err(|_| Error::DNS)?;
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).map_err(|_| Error::DNS)?;
+        let mut reply = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut reply).map_err(|_| Error::DNS)?;
+        if reply.len() < 2 || reply[..2] != packet[..2] {
+            return Err(Error::DNS);
+        }
+        Ok(reply)
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<Exchange>> {
+        dns::parse_mx_reply(&self.query(domain, dns::TYPE_MX)?)
+    }
+    fn addresses(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok((host, port)
+            .to_socket_addrs()
+            .map_err(|_| Error::DNS)?
+            .collect())
+    }
+    fn reverse(&self, ip: IpAddr) -> Result<Vec<String>> {
+        let name = dns::reverse_name(ip);
+        dns::parse_ptr_reply(&self.query(name.as_str(), dns::TYPE_PTR)?)
+    }
+}
+
+/// In-memory resolver answering only from the records it was given.
+/// Hosts without an entry that parse as IP addresses resolve to themselves.
+#[derive(Clone, Default)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<Exchange>>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+    pub fn mx(&mut self, domain: &str, preference: u16, host: &str) -> &mut StaticResolver {
+        self.mx
+            .entry(domain.to_lowercase())
+            .or_default()
+            .push(Exchange::new(preference, host.to_string()));
+        self
+    }
+    pub fn host(&mut self, host: &str, address: IpAddr) -> &mut StaticResolver {
+        self.hosts
+            .entry(host.to_lowercase())
+            .or_default()
+            .push(address);
+        self
+    }
+    pub fn ptr(&mut self, address: IpAddr, host: &str) -> &mut StaticResolver {
+        self.names
+            .entry(address)
+            .or_default()
+            .push(host.to_string());
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<Exchange>> {
+        Ok(self
+            .mx
+            .get(&domain.to_lowercase())
+            .cloned()
+            .unwrap_or_default())
+    }
+    fn addresses(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        if let Some(addresses) = self.hosts.get(&host.to_lowercase()) {
+            Ok(addresses
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, port))
+                .collect())
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            Ok(vec![SocketAddr::new(ip, port)])
+        } else {
+            Err(Error::DNS)
+        }
+    }
+    fn reverse(&self, ip: IpAddr) -> Result<Vec<String>> {
+        Ok(self.names.get(&ip).cloned().unwrap_or_default())
+    }
+}
+
+mod dns {
+    use super::Exchange;
+    use crate::{Error, Result};
+    use std::net::IpAddr;
+
+    pub(super) const TYPE_PTR: u16 = 12;
+    pub(super) const TYPE_MX: u16 = 15;
+    const CLASS_IN: u16 = 1;
+
+    pub(super) fn query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+        let mut packet = vec![];
+        packet.extend_from_slice(&id.to_be_bytes());
+        // recursion desired, one question
+        packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        for label in name.trim_end_matches('.').split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(Error::DNS);
+            }
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        Ok(packet)
+    }
+
+    /// The `in-addr.arpa` or `ip6.arpa` name PTR records of `ip` live under.
+    pub(super) fn reverse_name(ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let o = ip.octets();
+                format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+            }
+            IpAddr::V6(ip) => {
+                let mut name = String::new();
+                for byte in ip.octets().iter().rev() {
+                    name.push_str(format!("{:x}.{:x}.", byte & 0x0f, byte >> 4).as_str());
+                }
+                name.push_str("ip6.arpa");
+                name
+            }
+        }
+    }
+
+    /// Whether the reply was cut to fit in a datagram.
+    pub(super) fn truncated(packet: &[u8]) -> Result<bool> {
+        Ok(read_u16(packet, 2)? & 0x0200 != 0)
+    }
+
+    fn read_u16(packet: &[u8], at: usize) -> Result<u16> {
+        packet
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or(Error::DNS)
+    }
+
+    /// Reads a possibly compressed name starting at `at` and returns it
+    /// with the offset right after the name in the original position.
+    fn read_name(packet: &[u8], at: usize) -> Result<(String, usize)> {
+        let mut labels: Vec<String> = vec![];
+        let mut pos = at;
+        let mut end = None;
+        let mut jumps = 0;
+        loop {
+            let len = *packet.get(pos).ok_or(Error::DNS)? as usize;
+            if len & 0xc0 == 0xc0 {
+                jumps += 1;
+                if jumps > 16 {
+                    return Err(Error::DNS);
+                }
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                pos = (read_u16(packet, pos)? & 0x3fff) as usize;
+            } else if len == 0 {
+                return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+            } else {
+                let label = packet.get(pos + 1..pos + 1 + len).ok_or(Error::DNS)?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                pos += 1 + len;
+            }
+        }
+    }
+
+    /// Offsets of the data of every answer of type `rtype` in `packet`.
+    fn answers(packet: &[u8], rtype: u16) -> Result<Vec<usize>> {
+        let flags = read_u16(packet, 2)?;
+        // a domain that doesn't exist (NXDOMAIN) has no host to fall
+        // back to either, so it fails like any other error code
+        if flags & 0x000f != 0 {
+            return Err(Error::DNS);
+        }
+        let questions = read_u16(packet, 4)?;
+        let count = read_u16(packet, 6)?;
+        let mut pos = 12;
+        for _ in 0..questions {
+            pos = read_name(packet, pos)?.1 + 4;
+        }
+        let mut answers = vec![];
+        for _ in 0..count {
+            pos = read_name(packet, pos)?.1;
+            let rdlength = read_u16(packet, pos + 8)? as usize;
+            let rdata = pos + 10;
+            if read_u16(packet, pos)? == rtype {
+                answers.push(rdata);
+            }
+            pos = rdata + rdlength;
+        }
+        Ok(answers)
+    }
+
+    pub(super) fn parse_mx_reply(packet: &[u8]) -> Result<Vec<Exchange>> {
+        let mut exchanges = vec![];
+        let mut null_mx = false;
+        for rdata in answers(packet, TYPE_MX)? {
+            let preference = read_u16(packet, rdata)?;
+            let (host, _) = read_name(packet, rdata + 2)?;
+            if host.is_empty() {
+                null_mx = true;
+            } else {
+                exchanges.push(Exchange::new(preference, host));
+            }
+        }
+        // a null MX (RFC 7505) means the domain accepts no mail
+        if null_mx && exchanges.is_empty() {
+            Err(Error::Policy)
+        } else {
+            Ok(exchanges)
+        }
+    }
+
+    pub(super) fn parse_ptr_reply(packet: &[u8]) -> Result<Vec<String>> {
+        answers(packet, TYPE_PTR)?
+            .into_iter()
+            .map(|rdata| Ok(read_name(packet, rdata)?.0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// A reply to `query` with MX records of the given preference and
+    /// host, each pointing back to the question's name.
+    fn mx_reply(query: &[u8], flags: u16, exchanges: &[(u16, &str)]) -> Vec<u8> {
+        let mut reply = query[..2].to_vec();
+        reply.extend_from_slice(&flags.to_be_bytes());
+        reply.extend_from_slice(&[0, 1]);
+        reply.extend_from_slice(&(exchanges.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 0]);
+        reply.extend_from_slice(&query[12..]);
+        for (preference, host) in exchanges {
+            let mut rdata = preference.to_be_bytes().to_vec();
+            for label in host.split('.') {
+                rdata.push(label.len() as u8);
+                rdata.extend_from_slice(label.as_bytes());
+            }
+            rdata.push(0);
+            reply.extend_from_slice(&[0xc0, 12]);
+            reply.extend_from_slice(&dns::TYPE_MX.to_be_bytes());
+            reply.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+            reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            reply.extend_from_slice(&rdata);
+        }
+        reply
+    }
+
+    /// A nameserver that answers every UDP query with an empty truncated
+    /// reply and only gives the records over TCP.
+    fn truncating_nameserver(exchanges: &'static [(u16, &'static str)]) -> SocketAddr {
+        let (udp, tcp) = loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()) {
+                break (udp, tcp);
+            }
+        };
+        let address = udp.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = udp.recv_from(&mut buf) {
+                let reply = mx_reply(&buf[..len], 0x8380, &[]);
+                let _ = udp.send_to(&reply, peer);
+            }
+        });
+        thread::spawn(move || {
+            for mut stream in tcp.incoming().flatten() {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).unwrap();
+                let reply = mx_reply(&query, 0x8180, exchanges);
+                let mut message = (reply.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&reply);
+                stream.write_all(&message).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn truncated_reply_is_asked_again_over_tcp() {
+        let nameserver = truncating_nameserver(&[(10, "mx1.example.com"), (20, "mx2.example.com")]);
+        let mut resolver = SystemResolver::new();
+        resolver.nameserver(nameserver).timeout(2);
+        assert_eq!(
+            resolver.mx("example.com").unwrap(),
+            vec![
+                Exchange::new(10, "mx1.example.com".to_string()),
+                Exchange::new(20, "mx2.example.com".to_string()),
+            ]
+        );
+    }
+
+    fn mx_query() -> Vec<u8> {
+        dns::query(0x1234, "example.com", dns::TYPE_MX).unwrap()
+    }
+
+    #[test]
+    fn query_asks_one_question() {
+        assert_eq!(
+            mx_query(),
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07example\x03com\x00\x00\x0f\x00\x01"
+        );
+        assert!(dns::query(1, "a..example.com", dns::TYPE_MX).is_err());
+        assert!(dns::query(1, &"a".repeat(64), dns::TYPE_MX).is_err());
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            dns::reverse_name("192.0.2.10".parse().unwrap()),
+            "10.2.0.192.in-addr.arpa"
+        );
+        let name = dns::reverse_name("2001:db8::1".parse().unwrap());
+        assert!(name.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(name.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[test]
+    fn mx_reply_is_parsed() {
+        let reply = mx_reply(
+            &mx_query(),
+            0x8180,
+            &[(20, "mx2.example.com"), (10, "mx1.example.com")],
+        );
+        assert_eq!(
+            dns::parse_mx_reply(&reply).unwrap(),
+            vec![
+                Exchange::new(20, "mx2.example.com".to_string()),
+                Exchange::new(10, "mx1.example.com".to_string()),
+            ]
+        );
+        assert!(!dns::truncated(&reply).unwrap());
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        let mut reply = mx_reply(&mx_query(), 0x8180, &[]);
+        reply[7] = 1;
+        // mail.<the question's name>
+        let rdata = [&[0, 5, 4][..], b"mail", &[0xc0, 12]].concat();
+        reply.extend_from_slice(&[
+            0xc0,
+            12,
+            0,
+            15,
+            0,
+            1,
+            0,
+            0,
+            0x0e,
+            0x10,
+            0,
+            rdata.len() as u8,
+        ]);
+        reply.extend_from_slice(&rdata);
+        assert_eq!(
+            dns::parse_mx_reply(&reply).unwrap(),
+            vec![Exchange::new(5, "mail.example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn null_mx_refuses_mail() {
+        let reply = mx_reply(&mx_query(), 0x8180, &[(0, "")]);
+        assert!(matches!(dns::parse_mx_reply(&reply), Err(Error::Policy)));
+    }
+
+    #[test]
+    fn error_codes() {
+        let nxdomain = mx_reply(&mx_query(), 0x8183, &[]);
+        assert!(matches!(dns::parse_mx_reply(&nxdomain), Err(Error::DNS)));
+        let servfail = mx_reply(&mx_query(), 0x8182, &[]);
+        assert!(matches!(dns::parse_mx_reply(&servfail), Err(Error::DNS)));
+    }
+
+    #[test]
+    fn malformed_replies_fail() {
+        let reply = mx_reply(&mx_query(), 0x8180, &[(10, "mx1.example.com")]);
+        for len in 0..reply.len() - 1 {
+            assert!(
+                dns::parse_mx_reply(&reply[..len]).is_err(),
+                "cut at {}",
+                len
+            );
+        }
+        // a name that points at itself
+        let mut looping = mx_reply(&mx_query(), 0x8180, &[]);
+        looping[12..14].copy_from_slice(&[0xc0, 12]);
+        assert!(dns::parse_mx_reply(&looping).is_err());
+    }
+
+    #[test]
+    fn ptr_reply_is_parsed() {
+        let ip = "192.0.2.10".parse().unwrap();
+        let query = dns::query(7, &dns::reverse_name(ip), dns::TYPE_PTR).unwrap();
+        let mut reply = mx_reply(&query, 0x8180, &[]);
+        reply[7] = 1;
+        let rdata = [&[4][..], b"host", &[7], b"example", &[3], b"com", &[0]].concat();
+        reply.extend_from_slice(&[
+            0xc0,
+            12,
+            0,
+            12,
+            0,
+            1,
+            0,
+            0,
+            0x0e,
+            0x10,
+            0,
+            rdata.len() as u8,
+        ]);
+        reply.extend_from_slice(&rdata);
+        assert_eq!(
+            dns::parse_ptr_reply(&reply).unwrap(),
+            vec!["host.example.com"]
+        );
+        assert_eq!(dns::parse_mx_reply(&reply).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn mail_hosts_follow_preference() {
+        let mut resolver = StaticResolver::new();
+        // the trait's mx would be picked on a shared borrow
+        StaticResolver::mx(&mut resolver, "example.com", 20, "mx2.example.com").mx(
+            "example.com",
+            10,
+            "mx1.example.com",
+        );
+        assert_eq!(
+            mail_hosts(&resolver, "Example.com").unwrap(),
+            ["mx1.example.com", "mx2.example.com"]
+        );
+        assert_eq!(mail_hosts(&resolver, "other.org").unwrap(), ["other.org"]);
+    }
+}
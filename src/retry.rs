@@ -0,0 +1,157 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long to wait between attempts of a retriable operation. The n-th
+/// retry waits `initial_delay * multiplier^(n-1)`, capped at `max_delay`
+/// and spread by up to `jitter` (a fraction of the delay) either way.
+/// No retry is started that would end past `deadline`, counted from the
+/// first attempt.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    pub jitter: f64,
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            deadline: None,
+        }
+    }
+    /// Retries right away, as the mailer did before policies existed.
+    pub fn immediate() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::ZERO,
+            multiplier: 1.0,
+            max_delay: Duration::ZERO,
+            jitter: 0.0,
+            deadline: None,
+        }
+    }
+    /// Tries deferred mail again after 5 minutes, then less and less
+    /// often up to every 30 minutes, and gives up after an hour.
+    pub fn deferral() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(5 * 60),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30 * 60),
+            jitter: 0.1,
+            deadline: Some(Duration::from_secs(60 * 60)),
+        }
+    }
+    pub fn initial_delay(&mut self, value: Duration) -> &mut RetryPolicy {
+        self.initial_delay = value;
+        self
+    }
+    pub fn multiplier(&mut self, value: f64) -> &mut RetryPolicy {
+        self.multiplier = value;
+        self
+    }
+    pub fn max_delay(&mut self, value: Duration) -> &mut RetryPolicy {
+        self.max_delay = value;
+        self
+    }
+    pub fn jitter(&mut self, value: f64) -> &mut RetryPolicy {
+        self.jitter = value;
+        self
+    }
+    pub fn deadline(&mut self, value: Duration) -> &mut RetryPolicy {
+        self.deadline = Some(value);
+        self
+    }
+
+    /// The delay before retry number `attempt`, starting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        let base = base.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        // uniformly within [base * (1 - jitter), base * (1 + jitter)]
+        let factor = 1.0 + jitter * (2.0 * random_unit() - 1.0);
+        Duration::from_secs_f64((base * factor).max(0.0))
+    }
+
+    pub(crate) fn allows(&self, elapsed: Duration, delay: Duration) -> bool {
+        match self.deadline {
+            Some(deadline) => elapsed + delay <= deadline,
+            None => true,
+        }
+    }
+}
+
+/// A number in [0, 1) that is different on every call; good enough to
+/// spread retries apart, not for anything that needs real randomness.
+pub(crate) fn random_unit() -> f64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    // splitmix64
+    let mut z = nanos
+        .wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed))
+        .wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_back_off_from_one_second() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.initial_delay, Duration::from_secs(1));
+        assert_eq!(policy.multiplier, 2.0);
+        assert_eq!(policy.max_delay, Duration::from_secs(60));
+        assert_eq!(policy.jitter, 0.2);
+        assert_eq!(policy.deadline, None);
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let mut policy = RetryPolicy::new();
+        policy.jitter(0.0);
+        let delays = (1..=8).map(|attempt| policy.delay(attempt).as_secs());
+        assert_eq!(delays.collect::<Vec<_>>(), [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(RetryPolicy::immediate().delay(5), Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = RetryPolicy::new();
+        for _ in 0..100 {
+            let delay = policy.delay(3).as_secs_f64();
+            assert!((3.2..=4.8).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn deferral_gives_up_after_an_hour() {
+        let policy = RetryPolicy::deferral();
+        assert_eq!(policy.initial_delay, Duration::from_secs(5 * 60));
+        assert_eq!(policy.max_delay, Duration::from_secs(30 * 60));
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        assert!(policy.allows(minutes(30), minutes(30)));
+        assert!(!policy.allows(minutes(45), minutes(20)));
+        assert!(RetryPolicy::new().allows(minutes(600), minutes(60)));
+    }
+}
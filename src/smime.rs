@@ -0,0 +1,495 @@
+use crate::payload::{Boundaries, Part, Payload};
+use crate::pem::{self, der, OID_RSA};
+use crate::{Error, Result};
+use aes::Aes256;
+use cbc::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use mail_builder::{headers::content_type::ContentType, mime::BodyPart, mime::MimePart};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, KeyPair, RsaKeyPair},
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    rand_core::{OsRng, RngCore},
+    Pkcs1v15Encrypt, RsaPublicKey,
+};
+use std::{collections::HashMap, sync::Arc};
+
+const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+const OID_ENVELOPED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x03];
+const OID_CONTENT_TYPE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03];
+const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+const OID_SIGNING_TIME: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x05];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const TAG_IMPLICIT_0: u8 = 0x80;
+const NULL: &[u8] = &[0x05, 0x00];
+
+/// An X.509 certificate with an RSA key, to encrypt to or to send along
+/// with signatures.
+#[derive(Clone)]
+pub struct Certificate {
+    der: Vec<u8>,
+    /// The IssuerAndSerialNumber that names it in CMS.
+    id: Vec<u8>,
+    key: RsaPublicKey,
+}
+
+impl Certificate {
+    /// Reads the first certificate of a PEM file.
+    pub fn from_pem(pem: &str) -> Result<Certificate> {
+        certificates(pem)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Smime("no certificate found".to_string()))
+    }
+
+    fn from_der(encoded: Vec<u8>) -> Option<Certificate> {
+        let (certificate, _) = pem::expect(&encoded, pem::TAG_SEQUENCE)?;
+        let (tbs, _) = pem::expect(certificate, pem::TAG_SEQUENCE)?;
+        // the version is optional and comes first
+        let tbs = match pem::read(tbs)? {
+            (TAG_CONTEXT_0, _, rest) => rest,
+            _ => tbs,
+        };
+        let (serial, rest) = pem::element(tbs)?;
+        let (_, rest) = pem::element(rest)?;
+        let (issuer, rest) = pem::element(rest)?;
+        let (_, rest) = pem::element(rest)?;
+        let (_, rest) = pem::element(rest)?;
+        let (info, _) = pem::element(rest)?;
+        let key = match pem::public_key_info(info)? {
+            (OID_RSA, key) => RsaPublicKey::from_pkcs1_der(key).ok()?,
+            _ => return None,
+        };
+        if serial.first() != Some(&pem::TAG_INTEGER) {
+            return None;
+        }
+        let id = der(pem::TAG_SEQUENCE, &[issuer, serial].concat());
+        Some(Certificate {
+            der: encoded,
+            id,
+            key,
+        })
+    }
+}
+
+/// Every certificate of a PEM file, in order.
+fn certificates(pem: &str) -> Result<Vec<Certificate>> {
+    pem::decode(pem)
+        .into_iter()
+        .filter(|(label, _)| label == "CERTIFICATE")
+        .map(|(_, der)| {
+            Certificate::from_der(der)
+                .ok_or_else(|| Error::Smime("unsupported certificate".to_string()))
+        })
+        .collect()
+}
+
+/// A sender's certificate and RSA private key, which sign mails with
+/// SHA-256.
+#[derive(Clone)]
+pub struct SmimeSigner {
+    certificate: Certificate,
+    /// Intermediate certificates sent along so recipients can build the
+    /// path to a root they trust.
+    chain: Vec<Certificate>,
+    key: Arc<RsaKeyPair>,
+}
+
+impl SmimeSigner {
+    /// Reads the certificate, followed by any intermediates, and the
+    /// private key as PKCS#1 or PKCS#8.
+    pub fn from_pem(certificate: &str, key: &str) -> Result<SmimeSigner> {
+        let mut chain = certificates(certificate)?;
+        if chain.is_empty() {
+            return Err(Error::Smime("no certificate found".to_string()));
+        }
+        let certificate = chain.remove(0);
+        let key = pem::decode(key)
+            .into_iter()
+            .find_map(|(label, der)| match label.as_str() {
+                "RSA PRIVATE KEY" => RsaKeyPair::from_der(&der).ok(),
+                "PRIVATE KEY" => RsaKeyPair::from_pkcs8(&der).ok(),
+                _ => None,
+            })
+            .ok_or_else(|| Error::Smime("no RSA private key found".to_string()))?;
+        let public_key = RsaPublicKey::from_pkcs1_der(key.public_key().as_ref()).ok();
+        if public_key.as_ref() != Some(&certificate.key) {
+            return Err(Error::Smime(
+                "the key doesn't belong to the certificate".to_string(),
+            ));
+        }
+        Ok(SmimeSigner {
+            certificate,
+            chain,
+            key: Arc::new(key),
+        })
+    }
+
+    /// A detached CMS SignedData over `content`, made at the unix time
+    /// `time`.
+    fn sign(&self, content: &Payload, time: i64) -> Result<Vec<u8>> {
+        let mut context = digest::Context::new(&digest::SHA256);
+        content.write(|chunk| {
+            context.update(chunk);
+            Ok(())
+        })?;
+        let mut attributes = [
+            attribute(OID_CONTENT_TYPE, der(pem::TAG_OID, OID_DATA)),
+            attribute(OID_SIGNING_TIME, signing_time(time)),
+            attribute(
+                OID_MESSAGE_DIGEST,
+                der(pem::TAG_OCTET_STRING, context.finish().as_ref()),
+            ),
+        ];
+        // what's signed is the attributes as a DER SET, which is sorted
+        attributes.sort();
+        let mut signed = der(pem::TAG_SET, &attributes.concat());
+        let mut signature = vec![0; self.key.public_modulus_len()];
+        self.key
+            .sign(
+                &signature::RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                &signed,
+                &mut signature,
+            )
+            .map_err(|_| Error::Smime("signing failed".to_string()))?;
+        // in the SignerInfo they are tagged [0] instead
+        signed[0] = TAG_CONTEXT_0;
+        let digest_algorithm = algorithm(OID_SHA256, NULL);
+        let signer_info = der(
+            pem::TAG_SEQUENCE,
+            &[
+                der(pem::TAG_INTEGER, &[1]),
+                self.certificate.id.clone(),
+                digest_algorithm.clone(),
+                signed,
+                algorithm(OID_RSA, NULL),
+                der(pem::TAG_OCTET_STRING, &signature),
+            ]
+            .concat(),
+        );
+        let certificates = std::iter::once(&self.certificate)
+            .chain(self.chain.iter())
+            .map(|certificate| certificate.der.as_slice())
+            .collect::<Vec<_>>()
+            .concat();
+        let signed_data = der(
+            pem::TAG_SEQUENCE,
+            &[
+                der(pem::TAG_INTEGER, &[1]),
+                der(pem::TAG_SET, &digest_algorithm),
+                der(pem::TAG_SEQUENCE, &der(pem::TAG_OID, OID_DATA)),
+                der(TAG_CONTEXT_0, &certificates),
+                der(pem::TAG_SET, &signer_info),
+            ]
+            .concat(),
+        );
+        Ok(content_info(OID_SIGNED_DATA, signed_data))
+    }
+}
+
+/// Certificates and keys for S/MIME by address: a signer for each
+/// sender that signs and a certificate for each recipient mails are
+/// encrypted to.
+#[derive(Clone, Default)]
+pub struct Smime {
+    signers: HashMap<String, SmimeSigner>,
+    certificates: HashMap<String, Certificate>,
+}
+
+impl Smime {
+    pub fn new() -> Smime {
+        Smime::default()
+    }
+    pub fn signer(&mut self, address: &str, value: SmimeSigner) -> &mut Smime {
+        self.signers.insert(address.to_lowercase(), value);
+        self
+    }
+    pub fn certificate(&mut self, address: &str, value: Certificate) -> &mut Smime {
+        self.certificates.insert(address.to_lowercase(), value);
+        self
+    }
+    pub fn has_signer(&self, address: &str) -> bool {
+        self.signers.contains_key(address.to_lowercase().as_str())
+    }
+    pub fn has_certificate(&self, address: &str) -> bool {
+        self.certificates
+            .contains_key(address.to_lowercase().as_str())
+    }
+
+    /// `body` as multipart/signed (RFC 8551), signed by the signer of
+    /// `from`.
+    pub(crate) fn sign<'x>(
+        &self,
+        from: &str,
+        body: Part<'x>,
+        time: i64,
+        boundaries: &mut Boundaries,
+    ) -> Result<Part<'x>> {
+        let signer = self
+            .signers
+            .get(from.to_lowercase().as_str())
+            .ok_or_else(|| Error::Smime(format!("no signing certificate for {}", from)))?;
+        let content = Payload::new(vec![], body);
+        let signature = signer.sign(&content, time)?;
+        let signature = MimePart::new(
+            ContentType::new("application/pkcs7-signature").attribute("name", "smime.p7s"),
+            BodyPart::Binary(signature.into()),
+        )
+        .attachment("smime.p7s");
+        Ok(boundaries.multipart(
+            ContentType::new("multipart/signed")
+                .attribute("protocol", "application/pkcs7-signature")
+                .attribute("micalg", "sha-256"),
+            vec![Part::Payload(content), Part::Mime(signature)],
+        ))
+    }
+
+    /// `body` as application/pkcs7-mime, encrypted with AES-256-CBC to
+    /// the certificate of `to`. Unlike other parts, it is held in memory
+    /// whole, attachment files included.
+    pub(crate) fn encrypt<'x>(&self, to: &str, body: Part<'x>) -> Result<Part<'x>> {
+        let certificate = self
+            .certificates
+            .get(to.to_lowercase().as_str())
+            .ok_or_else(|| Error::Smime(format!("no certificate for {}", to)))?;
+        let content = Payload::new(vec![], body).to_bytes()?;
+        let mut key = [0; 32];
+        let mut iv = [0; 16];
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut iv);
+        let encrypted = cbc::Encryptor::<Aes256>::new(&key.into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(&content);
+        let encrypted_key = certificate
+            .key
+            .encrypt(&mut OsRng, Pkcs1v15Encrypt, &key)
+            .map_err(|_| Error::Smime(format!("can't encrypt to the certificate of {}", to)))?;
+        let recipient_info = der(
+            pem::TAG_SEQUENCE,
+            &[
+                der(pem::TAG_INTEGER, &[0]),
+                certificate.id.clone(),
+                algorithm(OID_RSA, NULL),
+                der(pem::TAG_OCTET_STRING, &encrypted_key),
+            ]
+            .concat(),
+        );
+        let encrypted_content_info = der(
+            pem::TAG_SEQUENCE,
+            &[
+                der(pem::TAG_OID, OID_DATA),
+                algorithm(OID_AES256_CBC, &der(pem::TAG_OCTET_STRING, &iv)),
+                der(TAG_IMPLICIT_0, &encrypted),
+            ]
+            .concat(),
+        );
+        let enveloped_data = der(
+            pem::TAG_SEQUENCE,
+            &[
+                der(pem::TAG_INTEGER, &[0]),
+                der(pem::TAG_SET, &recipient_info),
+                encrypted_content_info,
+            ]
+            .concat(),
+        );
+        let part = MimePart::new(
+            ContentType::new("application/pkcs7-mime")
+                .attribute("smime-type", "enveloped-data")
+                .attribute("name", "smime.p7m"),
+            BodyPart::Binary(content_info(OID_ENVELOPED_DATA, enveloped_data).into()),
+        )
+        .attachment("smime.p7m");
+        Ok(Part::Mime(part))
+    }
+}
+
+fn content_info(oid: &[u8], content: Vec<u8>) -> Vec<u8> {
+    let content_type = der(pem::TAG_OID, oid);
+    der(
+        pem::TAG_SEQUENCE,
+        &[content_type, der(TAG_CONTEXT_0, &content)].concat(),
+    )
+}
+
+fn algorithm(oid: &[u8], parameters: &[u8]) -> Vec<u8> {
+    der(
+        pem::TAG_SEQUENCE,
+        &[der(pem::TAG_OID, oid).as_slice(), parameters].concat(),
+    )
+}
+
+fn attribute(oid: &[u8], value: Vec<u8>) -> Vec<u8> {
+    der(
+        pem::TAG_SEQUENCE,
+        &[der(pem::TAG_OID, oid), der(pem::TAG_SET, &value)].concat(),
+    )
+}
+
+/// The unix time `time` as UTCTime, or GeneralizedTime outside the
+/// years UTCTime covers (RFC 5652 section 11.3).
+fn signing_time(time: i64) -> Vec<u8> {
+    let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+    // days to a civil date, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let (era, day_of_era) = (z.div_euclid(146097), z.rem_euclid(146097));
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    let rest = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    if (1950..2050).contains(&year) {
+        der(
+            TAG_UTC_TIME,
+            format!("{:02}{}", year % 100, rest).as_bytes(),
+        )
+    } else {
+        der(
+            TAG_GENERALIZED_TIME,
+            format!("{:04}{}", year, rest).as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockDecryptMut;
+    use base64::{engine::general_purpose, Engine};
+    use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
+
+    const SENDER_CERTIFICATE: &str = include_str!("../testdata/smime-sender.crt");
+    const SENDER_KEY: &str = include_str!("../testdata/smime-sender.key");
+    const RECIPIENT_CERTIFICATE: &str = include_str!("../testdata/smime-recipient.crt");
+    const RECIPIENT_KEY: &str = include_str!("../testdata/smime-recipient.key");
+
+    fn body() -> Part<'static> {
+        Part::Mime(MimePart::new_text("Hello, S/MIME"))
+    }
+
+    /// The contents of the `[0]` of a ContentInfo of type `oid`.
+    fn content(info: &[u8], oid: &[u8]) -> Vec<u8> {
+        let (info, rest) = pem::expect(info, pem::TAG_SEQUENCE).unwrap();
+        assert!(rest.is_empty());
+        let (found, info) = pem::expect(info, pem::TAG_OID).unwrap();
+        assert_eq!(found, oid);
+        let (content, _) = pem::expect(info, TAG_CONTEXT_0).unwrap();
+        content.to_vec()
+    }
+
+    #[test]
+    fn signed_data_verifies_against_certificate() {
+        let signer = SmimeSigner::from_pem(SENDER_CERTIFICATE, SENDER_KEY).unwrap();
+        let payload = Payload::new(vec![], body());
+        let signed = content(
+            &signer.sign(&payload, 1_700_000_000).unwrap(),
+            OID_SIGNED_DATA,
+        );
+        let (signed_data, _) = pem::expect(&signed, pem::TAG_SEQUENCE).unwrap();
+        let (version, rest) = pem::expect(signed_data, pem::TAG_INTEGER).unwrap();
+        assert_eq!(version, [1]);
+        let (_, rest) = pem::expect(rest, pem::TAG_SET).unwrap();
+        let (_, rest) = pem::expect(rest, pem::TAG_SEQUENCE).unwrap();
+        let (certificates, rest) = pem::expect(rest, TAG_CONTEXT_0).unwrap();
+        assert_eq!(certificates, signer.certificate.der.as_slice());
+        let (signer_infos, _) = pem::expect(rest, pem::TAG_SET).unwrap();
+        let (signer_info, _) = pem::expect(signer_infos, pem::TAG_SEQUENCE).unwrap();
+        let (_, rest) = pem::element(signer_info).unwrap();
+        let (id, rest) = pem::element(rest).unwrap();
+        assert_eq!(id, signer.certificate.id.as_slice());
+        let (_, rest) = pem::element(rest).unwrap();
+        let (attributes, rest) = pem::element(rest).unwrap();
+        let (_, rest) = pem::element(rest).unwrap();
+        let (signature, _) = pem::expect(rest, pem::TAG_OCTET_STRING).unwrap();
+        // the message digest attribute is the hash of the content
+        let digest = digest::digest(&digest::SHA256, &payload.to_bytes().unwrap());
+        let digest = der(pem::TAG_OCTET_STRING, digest.as_ref());
+        assert!(attributes.windows(digest.len()).any(|w| w == digest));
+        // the signature covers the attributes retagged as a SET
+        let mut signed_attributes = attributes.to_vec();
+        signed_attributes[0] = pem::TAG_SET;
+        let public_key = signer.key.public_key().as_ref().to_vec();
+        signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, public_key)
+            .verify(&signed_attributes, signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn key_of_other_certificate_is_refused() {
+        assert!(SmimeSigner::from_pem(SENDER_CERTIFICATE, RECIPIENT_KEY).is_err());
+    }
+
+    #[test]
+    fn enveloped_data_decrypts_with_recipient_key() {
+        let mut smime = Smime::new();
+        smime.certificate(
+            "Recipient@example.com",
+            Certificate::from_pem(RECIPIENT_CERTIFICATE).unwrap(),
+        );
+        let part = smime.encrypt("recipient@example.com", body()).unwrap();
+        let mime = Payload::new(vec![], part).to_bytes().unwrap();
+        let mime = String::from_utf8(mime).unwrap();
+        let (headers, encoded) = mime.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("application/pkcs7-mime"));
+        let encoded = encoded
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        let info = general_purpose::STANDARD.decode(encoded).unwrap();
+        let enveloped = content(&info, OID_ENVELOPED_DATA);
+        let (enveloped_data, _) = pem::expect(&enveloped, pem::TAG_SEQUENCE).unwrap();
+        let (_, rest) = pem::expect(enveloped_data, pem::TAG_INTEGER).unwrap();
+        let (recipient_infos, rest) = pem::expect(rest, pem::TAG_SET).unwrap();
+        let (recipient_info, _) = pem::expect(recipient_infos, pem::TAG_SEQUENCE).unwrap();
+        let (_, info_rest) = pem::element(recipient_info).unwrap();
+        let (_, info_rest) = pem::element(info_rest).unwrap();
+        let (_, info_rest) = pem::element(info_rest).unwrap();
+        let (encrypted_key, _) = pem::expect(info_rest, pem::TAG_OCTET_STRING).unwrap();
+        let (encrypted_info, _) = pem::expect(rest, pem::TAG_SEQUENCE).unwrap();
+        let (_, rest) = pem::expect(encrypted_info, pem::TAG_OID).unwrap();
+        let (algorithm, rest) = pem::expect(rest, pem::TAG_SEQUENCE).unwrap();
+        let (oid, parameters) = pem::expect(algorithm, pem::TAG_OID).unwrap();
+        assert_eq!(oid, OID_AES256_CBC);
+        let (iv, _) = pem::expect(parameters, pem::TAG_OCTET_STRING).unwrap();
+        let (encrypted, _) = pem::expect(rest, TAG_IMPLICIT_0).unwrap();
+
+        let private_key = RsaPrivateKey::from_pkcs8_pem(RECIPIENT_KEY).unwrap();
+        let key = private_key.decrypt(Pkcs1v15Encrypt, encrypted_key).unwrap();
+        let decrypted = cbc::Decryptor::<Aes256>::new_from_slices(&key, iv)
+            .unwrap()
+            .decrypt_padded_vec_mut::<Pkcs7>(encrypted)
+            .unwrap();
+        let plain = Payload::new(vec![], body()).to_bytes().unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn signing_time_switches_to_generalized_time() {
+        assert_eq!(signing_time(0), der(TAG_UTC_TIME, b"700101000000Z"));
+        assert_eq!(
+            signing_time(2_524_608_000),
+            der(TAG_GENERALIZED_TIME, b"20500101000000Z")
+        );
+    }
+}
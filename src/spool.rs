@@ -0,0 +1,223 @@
+use crate::{Error, Mail, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static ENTRY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolState {
+    Queued,
+    Sending,
+    Sent,
+    Failed,
+    Deferred,
+}
+
+impl SpoolState {
+    /// Whether the mail still has to be sent. A mail left `Sending` by a
+    /// crash is sent again: without the server's final reply it can't be
+    /// told apart from one that never went out.
+    pub fn pending(&self) -> bool {
+        matches!(
+            self,
+            SpoolState::Queued | SpoolState::Sending | SpoolState::Deferred
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    state: SpoolState,
+    error: Option<String>,
+    mail: Mail,
+}
+
+/// A directory holding one file per outgoing mail along with how far
+/// sending it got. Every change replaces the file in one rename, so an
+/// entry is never seen half written, even after a crash.
+#[derive(Clone)]
+pub struct Spool {
+    directory: PathBuf,
+}
+
+impl Spool {
+    pub fn open(directory: PathBuf) -> Result<Spool> {
+        fs::create_dir_all(&directory)
+            .map_err(|_| Error::File(directory.to_string_lossy().to_string()))?;
+        Ok(Spool { directory })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.directory.join(format!("{}.toml", id))
+    }
+
+    fn read(&self, id: &str) -> Result<Entry> {
+        let path = self.path(id);
+        let error = || Error::File(path.to_string_lossy().to_string());
+        let content = fs::read_to_string(&path).map_err(|_| error())?;
+        toml::from_str(content.as_str()).map_err(|_| error())
+    }
+
+    fn write(&self, id: &str, entry: &Entry) -> Result<()> {
+        let path = self.path(id);
+        let temp = self.directory.join(format!(".{}.tmp", id));
+        let error = |path: &Path| Error::File(path.to_string_lossy().to_string());
+        let content = toml::to_string(entry).map_err(|_| error(&path))?;
+        let mut file = fs::File::create(&temp).map_err(|_| error(&temp))?;
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|_| error(&temp))?;
+        fs::rename(&temp, &path).map_err(|_| error(&path))
+    }
+
+    /// Adds `mail` as queued and returns its id. Ids sort in the order
+    /// mails were added.
+    pub fn enqueue(&self, mail: &Mail) -> Result<String> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        let count = ENTRY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{:020}-{}-{:06}", time, std::process::id(), count);
+        self.write(
+            id.as_str(),
+            &Entry {
+                state: SpoolState::Queued,
+                error: None,
+                mail: mail.clone(),
+            },
+        )?;
+        Ok(id)
+    }
+
+    /// Records how far sending the mail `id` got, with the error that
+    /// stopped it, if any.
+    pub fn update(&self, id: &str, state: SpoolState, error: Option<&Error>) -> Result<()> {
+        let mut entry = self.read(id)?;
+        entry.state = state;
+        entry.error = error.map(|e| format!("{:?}", e));
+        self.write(id, &entry)
+    }
+
+    /// Marks the mail `id` as sending, keeping the Message-ID and Date
+    /// `mail` is sent with so a later run sends it with the same ones.
+    pub fn sending(&self, id: &str, mail: &Mail) -> Result<()> {
+        let mut entry = self.read(id)?;
+        entry.state = SpoolState::Sending;
+        entry.error = None;
+        entry.mail.message_id = mail.message_id.clone();
+        entry.mail.date = mail.date;
+        self.write(id, &entry)
+    }
+
+    pub fn state(&self, id: &str) -> Result<SpoolState> {
+        Ok(self.read(id)?.state)
+    }
+
+    /// The ids of all entries, oldest first.
+    pub fn ids(&self) -> Result<Vec<String>> {
+        let error = || Error::File(self.directory.to_string_lossy().to_string());
+        let mut ids = fs::read_dir(&self.directory)
+            .map_err(|_| error())?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".toml")
+                    .filter(|id| !id.starts_with('.'))
+                    .map(str::to_string)
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// The mails still to be sent, oldest first.
+    pub fn pending(&self) -> Result<Vec<(String, Mail)>> {
+        let mut pending = vec![];
+        for id in self.ids()? {
+            let entry = self.read(id.as_str())?;
+            if entry.state.pending() {
+                pending.push((id, entry.mail));
+            }
+        }
+        Ok(pending)
+    }
+}
+
+/// The spool entries of the mails of one post, by their index in it.
+pub(crate) struct SpoolBatch {
+    spool: Spool,
+    ids: Vec<String>,
+}
+
+impl SpoolBatch {
+    pub(crate) fn new(spool: Spool, ids: Vec<String>) -> SpoolBatch {
+        SpoolBatch { spool, ids }
+    }
+
+    pub(crate) fn update(
+        &self,
+        index: usize,
+        state: SpoolState,
+        error: Option<&Error>,
+    ) -> Result<()> {
+        self.spool.update(self.ids[index].as_str(), state, error)
+    }
+
+    pub(crate) fn sending(&self, index: usize, mail: &Mail) -> Result<()> {
+        self.spool.sending(self.ids[index].as_str(), mail)
+    }
+
+    pub(crate) fn record(&self, index: usize, result: &Result<()>) -> Result<()> {
+        match result {
+            Ok(_) => self.update(index, SpoolState::Sent, None),
+            // left for the next run to try again
+            Err(e) if e.deferrable() || e.retriable() => {
+                self.update(index, SpoolState::Deferred, Some(e))
+            }
+            Err(e) => self.update(index, SpoolState::Failed, Some(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Attachment, Content};
+
+    fn spool() -> Spool {
+        let directory = std::env::temp_dir().join(format!("spool-test-{}", Mail::new_id()));
+        Spool::open(directory).unwrap()
+    }
+
+    #[test]
+    fn attachments_survive_the_spool() {
+        let spool = spool();
+        let mail = Mail {
+            attachments: vec![
+                Attachment::new("/tmp/report.pdf".to_string()),
+                Attachment::bytes("data.bin".to_string(), vec![0, 1, 0xfe, 0xff]),
+            ],
+            ..Mail::default()
+        };
+        spool.enqueue(&mail).unwrap();
+        let loaded = spool.pending().unwrap().remove(0).1;
+        assert!(matches!(
+            &loaded.attachments[0].content,
+            Content::Path(path) if path == "/tmp/report.pdf"
+        ));
+        assert!(matches!(
+            &loaded.attachments[1].content,
+            Content::Bytes(bytes) if bytes == &[0, 1, 0xfe, 0xff]
+        ));
+        assert_eq!(loaded.attachments[1].filename.as_deref(), Some("data.bin"));
+        fs::remove_dir_all(&spool.directory).unwrap();
+    }
+}
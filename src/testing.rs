@@ -0,0 +1,138 @@
+//! A scripted SMTP server and a quiet logger for the unit tests.
+
+use crate::{Config, Event, Logger, Server};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+#[derive(Clone)]
+pub(crate) struct NullLogger;
+
+impl Logger for NullLogger {
+    fn client(&mut self, _data: &[u8]) {}
+    fn server(&mut self, _data: &[u8]) {}
+    fn disable(&mut self) {}
+    fn enable(&mut self) {}
+    fn event(&self, _event: Event) {}
+}
+
+/// Picks the reply to a command line, or to `""` for the greeting, in
+/// place of the default one. A 421 reply ends the session.
+type Script = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// An SMTP server on a local port that accepts every command unless its
+/// script says otherwise, and keeps the messages it receives.
+pub(crate) struct FakeServer {
+    pub(crate) port: u16,
+    sessions: Arc<AtomicUsize>,
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeServer {
+    pub(crate) fn start(script: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let messages = Arc::new(Mutex::new(vec![]));
+        let script: Arc<Script> = Arc::new(script);
+        let (count, received) = (sessions.clone(), messages.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (script, count, received) = (script.clone(), count.clone(), received.clone());
+                thread::spawn(move || session(stream, script.as_ref(), &count, &received));
+            }
+        });
+        FakeServer {
+            port,
+            sessions,
+            messages,
+        }
+    }
+
+    pub(crate) fn server(&self) -> Server {
+        Server::new("127.0.0.1".to_string(), self.port)
+    }
+
+    /// Sessions that got as far as EHLO.
+    pub(crate) fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+/// A config with short timeouts that doesn't pipeline, so replies come
+/// in the order the script expects.
+pub(crate) fn config() -> Config {
+    let mut config = Config::new();
+    config.timeout(5).pipeline(false);
+    config
+}
+
+fn session(
+    stream: TcpStream,
+    script: &Script,
+    sessions: &AtomicUsize,
+    received: &Mutex<Vec<String>>,
+) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = |line: &str, default: &str| -> bool {
+        let reply = script(line).unwrap_or_else(|| default.to_string());
+        let _ = writer.write_all(format!("{}\r\n", reply).as_bytes());
+        !reply.starts_with("421") && !reply.starts_with("221")
+    };
+    if !reply("", "220 fake ESMTP") {
+        return;
+    }
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let command = line.trim_end().to_string();
+        let verb = command
+            .split([' ', ':'])
+            .next()
+            .unwrap_or("")
+            .to_uppercase();
+        let open = match verb.as_str() {
+            "EHLO" => {
+                sessions.fetch_add(1, Ordering::SeqCst);
+                reply(&command, "250-fake\r\n250 8BITMIME")
+            }
+            "DATA" => {
+                if !reply(&command, "354 go on") {
+                    return;
+                }
+                let mut message = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    if line == ".\r\n" {
+                        break;
+                    }
+                    message.push_str(&line);
+                }
+                received.lock().unwrap().push(message);
+                reply(".", "250 queued")
+            }
+            "QUIT" => reply(&command, "221 bye"),
+            _ => reply(&command, "250 ok"),
+        };
+        if !open {
+            return;
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+/// How long each phase of a session may take, after RFC 5321 section
+/// 4.5.3.2: the 220 greeting, replies to other commands, the 354 reply
+/// to DATA, writing each block of the message and the reply to the final
+/// dot. `message`, when set, also bounds one whole mail transaction.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub greeting: Duration,
+    pub command: Duration,
+    pub data_init: Duration,
+    pub data_block: Duration,
+    pub data_end: Duration,
+    pub message: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(30),
+            greeting: Duration::from_secs(5 * 60),
+            command: Duration::from_secs(5 * 60),
+            data_init: Duration::from_secs(2 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_end: Duration::from_secs(10 * 60),
+            message: None,
+        }
+    }
+    /// The same timeout for every phase.
+    pub fn uniform(value: Duration) -> Timeouts {
+        Timeouts {
+            connect: value,
+            greeting: value,
+            command: value,
+            data_init: value,
+            data_block: value,
+            data_end: value,
+            message: None,
+        }
+    }
+    pub fn connect(&mut self, value: Duration) -> &mut Timeouts {
+        self.connect = value;
+        self
+    }
+    pub fn greeting(&mut self, value: Duration) -> &mut Timeouts {
+        self.greeting = value;
+        self
+    }
+    pub fn command(&mut self, value: Duration) -> &mut Timeouts {
+        self.command = value;
+        self
+    }
+    pub fn data_init(&mut self, value: Duration) -> &mut Timeouts {
+        self.data_init = value;
+        self
+    }
+    pub fn data_block(&mut self, value: Duration) -> &mut Timeouts {
+        self.data_block = value;
+        self
+    }
+    pub fn data_end(&mut self, value: Duration) -> &mut Timeouts {
+        self.data_end = value;
+        self
+    }
+    pub fn message(&mut self, value: Duration) -> &mut Timeouts {
+        self.message = Some(value);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_follow_rfc_5321() {
+        let timeouts = Timeouts::default();
+        assert_eq!(timeouts.connect, Duration::from_secs(30));
+        assert_eq!(timeouts.greeting, Duration::from_secs(5 * 60));
+        assert_eq!(timeouts.command, Duration::from_secs(5 * 60));
+        assert_eq!(timeouts.data_init, Duration::from_secs(2 * 60));
+        assert_eq!(timeouts.data_block, Duration::from_secs(3 * 60));
+        assert_eq!(timeouts.data_end, Duration::from_secs(10 * 60));
+        assert_eq!(timeouts.message, None);
+    }
+
+    #[test]
+    fn uniform_sets_every_phase() {
+        let value = Duration::from_secs(7);
+        let timeouts = Timeouts::uniform(value);
+        let phases = [
+            timeouts.connect,
+            timeouts.greeting,
+            timeouts.command,
+            timeouts.data_init,
+            timeouts.data_block,
+            timeouts.data_end,
+        ];
+        assert!(phases.iter().all(|&phase| phase == value));
+        assert_eq!(timeouts.message, None);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{connection::MailerConnection, Error, Logger, Mail, Result};
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sender and recipients of a mail, as given to the server in
+/// `MAIL FROM` and `RCPT TO` rather than in the headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl Envelope {
+    pub fn new(from: String, to: Vec<String>) -> Envelope {
+        Envelope { from, to }
+    }
+}
+
+/// Where the mails posted through a `Mailer` are delivered.
+pub trait Transport: Send {
+    fn send(&mut self, mail: &Mail) -> Result<()>;
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: Logger> Transport for MailerConnection<L> {
+    fn send(&mut self, mail: &Mail) -> Result<()> {
+        self.send_mail(mail)
+    }
+    fn close(&mut self) -> Result<()> {
+        MailerConnection::close(self)
+    }
+}
+
+/// Pipes every mail to a local `sendmail`-compatible binary, which
+/// reads the recipients from the message headers.
+#[derive(Clone)]
+pub struct SendmailTransport {
+    command: String,
+}
+
+impl Default for SendmailTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SendmailTransport {
+    pub fn new() -> SendmailTransport {
+        SendmailTransport {
+            command: "sendmail".to_string(),
+        }
+    }
+    pub fn command(&mut self, value: String) -> &mut SendmailTransport {
+        self.command = value;
+        self
+    }
+}
+
+impl Transport for SendmailTransport {
+    fn send(&mut self, mail: &Mail) -> Result<()> {
+        let payload = mail.payload()?;
+        let mut child = Command::new(self.command.as_str())
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|_| Error::Transport(format!("failed to run {}", self.command)))?;
+        let written = child
+            .stdin
+            .take()
+            .map(|mut stdin| {
+                payload
+                    .write(|chunk| stdin.write_all(chunk).map_err(|_| Error::Network))
+                    .is_ok()
+            })
+            .unwrap_or(false);
+        let status = child
+            .wait()
+            .map_err(|_| Error::Transport(format!("failed to wait for {}", self.command)))?;
+        if written && status.success() {
+            Ok(())
+        } else {
+            Err(Error::Transport(format!(
+                "{} exited with {}",
+                self.command, status
+            )))
+        }
+    }
+}
+
+/// Writes every mail as a separate `.eml` file into a directory.
+#[derive(Clone)]
+pub struct FileTransport {
+    directory: PathBuf,
+}
+
+static FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl FileTransport {
+    pub fn new(directory: PathBuf) -> FileTransport {
+        FileTransport { directory }
+    }
+
+    fn file_name(&self) -> PathBuf {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        let count = FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.directory
+            .join(format!("{}-{}-{}.eml", time, std::process::id(), count))
+    }
+}
+
+impl Transport for FileTransport {
+    fn send(&mut self, mail: &Mail) -> Result<()> {
+        let payload = mail.payload()?;
+        let path = self.file_name();
+        let error = |_| Error::File(path.display().to_string());
+        let mut file = fs::File::create(&path).map_err(error)?;
+        let result = payload.write(|chunk| file.write_all(chunk).map_err(error));
+        if result.is_err() {
+            let _ = fs::remove_file(&path);
+        }
+        result
+    }
+}
+
+/// A mail recorded by a `MemoryTransport`.
+#[derive(Clone, Debug)]
+pub struct SentMail {
+    pub envelope: Envelope,
+    pub message: Vec<u8>,
+}
+
+/// Keeps every mail in memory instead of sending it. Clones share the
+/// same record, so a clone can be kept to inspect what a `Mailer` sent.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    sent: Arc<Mutex<Vec<SentMail>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+    pub fn sent(&self) -> Vec<SentMail> {
+        self.sent.lock().unwrap().clone()
+    }
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, mail: &Mail) -> Result<()> {
+        let message = mail.to_bytes()?;
+        self.sent.lock().unwrap().push(SentMail {
+            envelope: mail.envelope(),
+            message,
+        });
+        Ok(())
+    }
+}